@@ -5,11 +5,13 @@ use std::convert::TryFrom;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::str::FromStr;
 
+/// The first bytes of a block hash, which new blocks are mined on top of
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(try_from = "&str", into = "String")]
 pub struct ShortHash([u8; ShortHash::LENGTH]);
 
 impl ShortHash {
+    /// The length of a short hash, in bytes
     pub const LENGTH: usize = 6;
 
     pub fn bytes(self) -> [u8; ShortHash::LENGTH] {
@@ -57,11 +59,13 @@ impl Debug for ShortHash {
     }
 }
 
+/// A full SHA-256 block hash
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(try_from = "&str", into = "String")]
 pub struct Hash([u8; Hash::LENGTH]);
 
 impl Hash {
+    /// The length of a hash, in bytes
     pub const LENGTH: usize = 32;
 
     pub fn bytes(self) -> [u8; Hash::LENGTH] {
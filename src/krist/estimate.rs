@@ -0,0 +1,144 @@
+//! Mining success estimates derived from the krist target rule
+
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// The number of distinct hash scores - a score is the first 48 bits of the
+/// SHA-256 digest of a candidate block.
+pub const SCORE_SPACE: f64 = (1u64 << 48) as f64;
+
+/// The probability that a single hash solves a block with the given work.
+///
+/// A hash is a solution when its score is less than or equal to the work, so
+/// `work + 1` of the possible scores are successful.
+pub fn hash_probability(work: u64) -> f64 {
+    ((work as f64) + 1.0).min(SCORE_SPACE) / SCORE_SPACE
+}
+
+/// Expected mining results for a given hashrate and work value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// The hashrate, in hashes per second
+    pub hashrate: f64,
+
+    /// The current work value
+    pub work: u64,
+}
+
+impl Estimate {
+    pub fn new(hashrate: f64, work: u64) -> Self {
+        Self { hashrate, work }
+    }
+
+    /// The expected number of blocks found per second
+    pub fn blocks_per_second(&self) -> f64 {
+        self.hashrate * hash_probability(self.work)
+    }
+
+    /// The expected time to find a block, or `None` if no blocks are expected
+    /// to be found (e.g. the hashrate is zero)
+    pub fn expected_block_time(&self) -> Option<Duration> {
+        let rate = self.blocks_per_second();
+
+        if rate > 0.0 && (1.0 / rate).is_finite() {
+            Some(Duration::from_secs_f64((1.0 / rate).min(u64::MAX as f64)))
+        } else {
+            None
+        }
+    }
+
+    /// The chance of finding at least one block within the given duration
+    pub fn chance_within(&self, duration: Duration) -> f64 {
+        // block discovery is a poisson process, so the chance of finding no
+        // blocks in the period is e^(-expected blocks)
+        1.0 - (-self.blocks_per_second() * duration.as_secs_f64()).exp()
+    }
+
+    /// The expected amount of KST mined per day at the given block value
+    pub fn kst_per_day(&self, block_value: u32) -> f64 {
+        self.blocks_per_second() * 86400.0 * block_value as f64
+    }
+}
+
+/// Format a duration approximately, using the largest sensible unit
+pub fn format_duration(duration: Duration) -> String {
+    const UNITS: [(&str, f64); 5] = [
+        ("year", 365.25 * 86400.0),
+        ("day", 86400.0),
+        ("hour", 3600.0),
+        ("minute", 60.0),
+        ("second", 1.0),
+    ];
+
+    let secs = duration.as_secs_f64();
+    let (unit, size) = UNITS
+        .iter()
+        .copied()
+        .find(|&(_, size)| secs >= size)
+        .unwrap_or(UNITS[UNITS.len() - 1]);
+
+    let value = secs / size;
+    format!(
+        "{:.1} {}{}",
+        value,
+        unit,
+        if (value - 1.0).abs() < 0.05 { "" } else { "s" }
+    )
+}
+
+impl Display for Estimate {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.expected_block_time() {
+            Some(t) => write!(f, "~{} per block", format_duration(t))?,
+            None => write!(f, "no blocks expected")?,
+        }
+
+        write!(
+            f,
+            ", {:.1}% chance within an hour",
+            self.chance_within(Duration::from_secs(3600)) * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_probability() {
+        assert_eq!(hash_probability(0), 1.0 / SCORE_SPACE);
+        assert_eq!(hash_probability((1 << 47) - 1), 0.5);
+        assert_eq!(hash_probability(u64::MAX), 1.0);
+    }
+
+    #[test]
+    fn test_expected_block_time() {
+        // one in 2^16 hashes succeeds, so 2^16 h/s finds a block every second
+        let estimate = Estimate::new(65536.0, (1 << 32) - 1);
+        assert_eq!(estimate.expected_block_time(), Some(Duration::from_secs(1)));
+        assert_eq!(estimate.kst_per_day(2), 2.0 * 86400.0);
+
+        assert_eq!(Estimate::new(0.0, 100_000).expected_block_time(), None);
+    }
+
+    #[test]
+    fn test_chance_within() {
+        let estimate = Estimate::new(65536.0, (1 << 32) - 1);
+        let chance = estimate.chance_within(Duration::from_secs(1));
+        assert!((chance - (1.0 - (-1f64).exp())).abs() < 1e-9);
+
+        assert_eq!(
+            Estimate::new(0.0, 100_000).chance_within(Duration::from_secs(3600)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(1)), "1.0 second");
+        assert_eq!(format_duration(Duration::from_secs(90)), "1.5 minutes");
+        assert_eq!(format_duration(Duration::from_secs(86400 * 3)), "3.0 days");
+        assert_eq!(format_duration(Duration::from_millis(200)), "0.2 seconds");
+    }
+}
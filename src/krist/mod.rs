@@ -2,3 +2,6 @@
 
 pub mod address;
 pub mod block;
+pub mod estimate;
+pub mod name;
+pub mod nonce;
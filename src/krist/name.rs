@@ -0,0 +1,182 @@
+use super::address::{Address, InvalidAddress};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+
+/// A krist name, e.g. `example.kst`
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "&str", into = "String")]
+pub struct Name(String);
+
+impl Name {
+    /// The maximum length of a name, excluding the suffix
+    pub const MAX_LENGTH: usize = 64;
+
+    /// The suffix used to refer to names
+    pub const SUFFIX: &'static str = ".kst";
+
+    /// The set of allowed characters for names
+    pub const CHARS: &'static str = "1234567890abcdefghijklmnopqrstuvwxyz";
+
+    /// Get this name without the suffix
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// An error caused by an invalid name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidName {
+    #[error("invalid name length: {0}")]
+    InvalidLength(usize),
+
+    #[error("illegal character: {0} at index {1}")]
+    IllegalCharacter(char, usize),
+}
+
+impl FromStr for Name {
+    type Err = InvalidName;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.strip_suffix(Self::SUFFIX).unwrap_or(s);
+
+        if name.is_empty() || name.len() > Self::MAX_LENGTH {
+            return Err(InvalidName::InvalidLength(name.len()));
+        }
+
+        if let Some((i, c)) = name
+            .chars()
+            .enumerate()
+            .find(|&(_, c)| !Self::CHARS.contains(c))
+        {
+            return Err(InvalidName::IllegalCharacter(c, i));
+        }
+
+        Ok(Self(name.to_string()))
+    }
+}
+
+impl TryFrom<&str> for Name {
+    type Error = InvalidName;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::from_str(value)
+    }
+}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.0, Self::SUFFIX)
+    }
+}
+
+impl From<Name> for String {
+    fn from(name: Name) -> Self {
+        name.0
+    }
+}
+
+impl Debug for Name {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Name({})", self)
+    }
+}
+
+/// Who to mine for - either an address, or the owner of a name
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Recipient {
+    Address(Address),
+    Name(Name),
+}
+
+/// An error caused by a string that is neither an address nor a name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("not a valid address ({address}) or name ({name})")]
+pub struct InvalidRecipient {
+    pub address: InvalidAddress,
+    pub name: InvalidName,
+}
+
+impl FromStr for Recipient {
+    type Err = InvalidRecipient;
+
+    /// Parse an address or name. Strings ending in `.kst` are always names,
+    /// otherwise anything that is a valid address is treated as one.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.ends_with(Name::SUFFIX) {
+            return Name::from_str(s)
+                .map(Recipient::Name)
+                .map_err(|name| InvalidRecipient {
+                    address: Address::from_str(s).unwrap_err(),
+                    name,
+                });
+        }
+
+        match (Address::from_str(s), Name::from_str(s)) {
+            (Ok(address), _) => Ok(Recipient::Address(address)),
+            (_, Ok(name)) => Ok(Recipient::Name(name)),
+            (Err(address), Err(name)) => Err(InvalidRecipient { address, name }),
+        }
+    }
+}
+
+impl Display for Recipient {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Recipient::Address(address) => Display::fmt(address, f),
+            Recipient::Name(name) => Display::fmt(name, f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_valid_names() {
+        assert_eq!(Name::from_str("myshop.kst").unwrap().as_str(), "myshop");
+        assert_eq!(Name::from_str("myshop").unwrap().to_string(), "myshop.kst");
+    }
+
+    #[test]
+    fn check_invalid_names() {
+        assert_eq!(
+            Name::from_str(".kst").unwrap_err(),
+            InvalidName::InvalidLength(0)
+        );
+        assert_eq!(
+            Name::from_str("my-shop.kst").unwrap_err(),
+            InvalidName::IllegalCharacter('-', 2)
+        );
+        assert_eq!(
+            Name::from_str(&"a".repeat(65)).unwrap_err(),
+            InvalidName::InvalidLength(65)
+        );
+    }
+
+    #[test]
+    fn test_parse_recipient() {
+        assert_eq!(
+            Recipient::from_str("k5ztameslf").unwrap(),
+            Recipient::Address(Address::from_str("k5ztameslf").unwrap())
+        );
+        assert_eq!(
+            Recipient::from_str("myshop.kst").unwrap(),
+            Recipient::Name(Name::from_str("myshop").unwrap())
+        );
+        assert_eq!(
+            Recipient::from_str("myshop").unwrap(),
+            Recipient::Name(Name::from_str("myshop").unwrap())
+        );
+
+        // names ending with the suffix are never addresses
+        assert_eq!(
+            Recipient::from_str("k5ztameslf.kst").unwrap(),
+            Recipient::Name(Name::from_str("k5ztameslf").unwrap())
+        );
+
+        assert!(Recipient::from_str("Not valid!").is_err());
+    }
+}
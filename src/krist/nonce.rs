@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+use std::convert::{TryFrom, TryInto};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+
+/// A block nonce, in the wire form submitted to the krist node.
+///
+/// Miners search the nonce space with a `u64` counter, which is expanded into
+/// [`Nonce::LENGTH`] printable characters of [`Nonce::BITS_PER_CHAR`] bits
+/// each, least significant first. Every miner (including the OpenCL kernel,
+/// which is compiled with these constants) must use this encoding so that a
+/// nonce can always be traced back to the counter that produced it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "&str", into = "String")]
+pub struct Nonce([u8; Nonce::LENGTH]);
+
+impl Nonce {
+    /// The length of an encoded nonce, in bytes
+    pub const LENGTH: usize = 11;
+
+    /// The number of counter bits encoded in each nonce character
+    pub const BITS_PER_CHAR: usize = 6;
+
+    /// The value added to each group of counter bits to get a printable
+    /// character
+    pub const CHAR_OFFSET: u8 = 32;
+
+    /// Encode a single character of the nonce for the given counter
+    #[inline(always)]
+    pub fn encode_char(counter: u64, index: usize) -> u8 {
+        const MASK: u64 = (1 << Nonce::BITS_PER_CHAR) - 1;
+        ((counter >> (index * Self::BITS_PER_CHAR)) & MASK) as u8 + Self::CHAR_OFFSET
+    }
+
+    /// Encode the given counter into an existing buffer
+    #[inline(always)]
+    pub fn encode_into(counter: u64, buf: &mut [u8; Nonce::LENGTH]) {
+        for (i, v) in buf.iter_mut().enumerate() {
+            *v = Self::encode_char(counter, i);
+        }
+    }
+
+    /// Create a nonce from a counter value
+    pub fn from_counter(counter: u64) -> Self {
+        let mut buf = [0; Self::LENGTH];
+        Self::encode_into(counter, &mut buf);
+        Self(buf)
+    }
+
+    /// Get the counter value this nonce was encoded from
+    pub fn counter(&self) -> u64 {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| ((c - Self::CHAR_OFFSET) as u64) << (i * Self::BITS_PER_CHAR))
+            .fold(0, |a, b| a | b)
+    }
+
+    /// Get this nonce as a string slice
+    pub fn as_str(&self) -> &str {
+        // nonces are only constructed from printable ASCII, so this should
+        // never panic
+        std::str::from_utf8(&self.0).unwrap()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Nonce::LENGTH] {
+        &self.0
+    }
+}
+
+/// An error caused by an invalid nonce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidNonce {
+    #[error("invalid nonce length: {0}")]
+    InvalidLength(usize),
+
+    #[error("illegal character: {0:?} at index {1}")]
+    IllegalCharacter(char, usize),
+}
+
+impl TryFrom<&[u8]> for Nonce {
+    type Error = InvalidNonce;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let bytes: [u8; Self::LENGTH] = bytes
+            .try_into()
+            .map_err(|_| InvalidNonce::InvalidLength(bytes.len()))?;
+
+        // a valid character holds BITS_PER_CHAR bits, except for the last
+        // one which only holds the bits remaining in a u64
+        let max_value = |i: usize| {
+            let bits = (64 - i * Self::BITS_PER_CHAR).min(Self::BITS_PER_CHAR);
+            Self::CHAR_OFFSET + ((1u16 << bits) - 1) as u8
+        };
+
+        if let Some((i, &c)) = bytes
+            .iter()
+            .enumerate()
+            .find(|&(i, &c)| c < Self::CHAR_OFFSET || c > max_value(i))
+        {
+            return Err(InvalidNonce::IllegalCharacter(c as char, i));
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+impl FromStr for Nonce {
+    type Err = InvalidNonce;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s.as_bytes())
+    }
+}
+
+impl TryFrom<&str> for Nonce {
+    type Error = InvalidNonce;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::from_str(value)
+    }
+}
+
+impl From<u64> for Nonce {
+    fn from(counter: u64) -> Self {
+        Self::from_counter(counter)
+    }
+}
+
+impl Display for Nonce {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<Nonce> for String {
+    fn from(nonce: Nonce) -> Self {
+        nonce.to_string()
+    }
+}
+
+impl Debug for Nonce {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Nonce({:?}, counter {})", self.as_str(), self.counter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Nonce::from_counter(0).as_str(), "           ");
+        assert_eq!(Nonce::from_counter(1).as_str(), "!          ");
+        assert_eq!(Nonce::from_counter(64).as_str(), " !         ");
+        assert_eq!(Nonce::from_counter(u64::MAX).as_str(), "__________/");
+    }
+
+    #[test]
+    fn test_counter_round_trip() {
+        for &n in &[0, 1, 63, 64, 0xdead_beef, 1 << 63, u64::MAX] {
+            assert_eq!(Nonce::from_counter(n).counter(), n);
+            assert_eq!(
+                Nonce::from_str(Nonce::from_counter(n).as_str()),
+                Ok(n.into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_nonces() {
+        assert_eq!(
+            Nonce::from_str("abc").unwrap_err(),
+            InvalidNonce::InvalidLength(3)
+        );
+        assert_eq!(
+            Nonce::from_str("aaaaaaaaa\naa").unwrap_err(),
+            InvalidNonce::InvalidLength(12)
+        );
+        assert_eq!(
+            Nonce::from_str("AAAA\nAAAAAA").unwrap_err(),
+            InvalidNonce::IllegalCharacter('\n', 4)
+        );
+        assert_eq!(
+            Nonce::from_str("aaaaaaaaaaa").unwrap_err(),
+            InvalidNonce::IllegalCharacter('a', 0)
+        );
+        assert_eq!(
+            Nonce::from_str("__________0").unwrap_err(),
+            InvalidNonce::IllegalCharacter('0', 10)
+        );
+    }
+
+    #[test]
+    fn test_serialize_deserialize() {
+        let nonce = Nonce::from_counter(0x1234_5678_9abc_def0);
+
+        assert_eq!(serde_json::to_value(nonce).unwrap(), nonce.as_str());
+        assert_eq!(
+            nonce,
+            serde_json::from_str::<Nonce>(&serde_json::to_string(&nonce).unwrap()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! Kristforge is a hardware accelerated CPU and GPU [krist](https://krist.ceriat.net)
+//! miner. This library contains the miners themselves along with a client for
+//! the krist node, which the `kristforge` binary builds on.
+//!
+//! A minimal mining session creates miners with [`miner::create_miners`],
+//! gives each one a [`miner::interface::MinerInterface`] to receive targets
+//! and report solutions through, and pipes targets and solutions to and from
+//! a node connection created with [`network::connect`]. Miners share a
+//! [`miner::nonces::NonceAllocator`] so they never search the same nonces,
+//! and publish their status on a [`miner::events::EventBus`]:
+//!
+//! ```no_run
+//! use kristforge::krist::address::Address;
+//! use kristforge::miner::events::{EventBus, MinerId};
+//! use kristforge::miner::interface::MinerInterface;
+//! use kristforge::miner::nonces::NonceAllocator;
+//! use kristforge::miner::watch;
+//! use kristforge::miner::{create_miners, MinerConfig};
+//! use std::str::FromStr;
+//!
+//! let address = Address::from_str("k5ztameslf").unwrap();
+//! let (sol_tx, sol_rx) = futures::channel::mpsc::unbounded();
+//! let bus = EventBus::new();
+//! let nonces = NonceAllocator::new();
+//! let (target_tx, target_rx) = watch::channel();
+//!
+//! // log all miner events
+//! let events = bus.subscribe();
+//! std::thread::spawn(move || kristforge::miner::events::log_events(events));
+//!
+//! for (i, miner) in create_miners(MinerConfig::default()).unwrap().into_iter().enumerate() {
+//!     let interface = MinerInterface::new(address, bus.emitter(MinerId(i)), target_rx.clone(), sol_tx.clone())
+//!         .with_nonces(nonces.clone());
+//!     std::thread::spawn(move || kristforge::miner::run(miner, interface));
+//! }
+//!
+//! // send `Target`s through `target_tx` and submit solutions from `sol_rx`...
+//! # drop(sol_rx);
+//! ```
+
+pub mod krist;
+pub mod miner;
+pub mod network;
+
+use std::path::PathBuf;
+
+/// The directory kristforge stores its data in, such as logs, cached CPU
+/// kernel measurements and nonce progress
+pub fn data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|d| d.join(env!("CARGO_PKG_NAME")))
+}
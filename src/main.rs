@@ -1,20 +1,85 @@
-mod krist;
-mod miner;
-mod network;
-
-use crate::krist::address::Address;
-use crate::miner::interface::MinerInterface;
-use crate::miner::Target;
-use crate::network::{ClientMessage, ServerMessage};
+mod ui;
+
+use crate::ui::MinerUi;
+use crossbeam::atomic::AtomicCell;
 use futures::{future, StreamExt, TryFutureExt, TryStreamExt};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use itertools::Itertools;
+use kristforge::krist::address::Address;
+use kristforge::krist::estimate::{self, Estimate};
+use kristforge::krist::name::{Name, Recipient};
+use kristforge::miner::bench::{self, BenchConfig, BenchResult};
+use kristforge::miner::events::{self as miner_events, EventBus, MinerId};
+use kristforge::miner::gpu::select::DeviceKinds;
+use kristforge::miner::idle::{IdleConfig, IdleMonitor, LoadReader, ProcLoadReader};
+use kristforge::miner::interface::{format_hashrate, parse_hashrate, MinerInterface};
+use kristforge::miner::nonces::NonceAllocator;
+use kristforge::miner::pause::{Pause, PauseReason};
+use kristforge::miner::schedule::{Clock, LocalClock, Schedule, ScheduleConfig, UtcClock};
+use kristforge::miner::supervisor::{supervise, SupervisorConfig};
+use kristforge::miner::watch;
+use kristforge::miner::{self, MinerConfig, Target};
+use kristforge::network::{self, ClientMessage, NetConfig, NetworkError, ServerMessage};
 use log::LevelFilter;
-use miner::MinerConfig;
-use network::{NetConfig, NetworkError};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::{create_dir_all, File};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
+/// What to do when the owner of the name being mined for changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OwnerChangePolicy {
+    /// Keep mining for the original owner, but warn about it
+    Warn,
+
+    /// Pause mining until the name is owned by the original owner again
+    Pause,
+}
+
+impl FromStr for OwnerChangePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(OwnerChangePolicy::Warn),
+            "pause" => Ok(OwnerChangePolicy::Pause),
+            _ => Err(format!("invalid policy {:?}, expected warn or pause", s)),
+        }
+    }
+}
+
+/// Options for mining for the owner of a name
+#[derive(Debug, Clone, StructOpt)]
+pub struct NameConfig {
+    /// How often to check the owner of the name being mined for, in seconds
+    #[structopt(long, default_value = "300")]
+    name_refresh: u64,
+
+    /// What to do if the owner of the name being mined for changes (warn or
+    /// pause)
+    #[structopt(long, default_value = "pause")]
+    on_owner_change: OwnerChangePolicy,
+}
+
+/// Options for the mining session as a whole
+#[derive(Debug, Clone, StructOpt)]
+pub struct SessionConfig {
+    /// Don't save which nonces have been searched, which otherwise lets a
+    /// restart on the same block skip them
+    #[structopt(long)]
+    no_save_nonces: bool,
+
+    /// Show the hashrate of each CPU miner thread on its own line
+    #[structopt(long)]
+    show_threads: bool,
+
+    #[structopt(flatten)]
+    supervisor_cfg: SupervisorConfig,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about, author)]
 pub enum Opts {
@@ -35,9 +100,60 @@ pub enum Opts {
         #[structopt(flatten)]
         miner_cfg: MinerConfig,
 
-        /// The address to mine krist for
+        #[structopt(flatten)]
+        name_cfg: NameConfig,
+
+        #[structopt(flatten)]
+        idle_cfg: IdleConfig,
+
+        #[structopt(flatten)]
+        schedule_cfg: ScheduleConfig,
+
+        #[structopt(flatten)]
+        session_cfg: SessionConfig,
+
+        /// The address or name (e.g. `example.kst`) to mine krist for. Names
+        /// are resolved to their owner's address when mining starts.
         #[structopt(env = "KRISTFORGE_ADDRESS")]
-        address: Address,
+        address: Recipient,
+    },
+
+    /// Benchmark each CPU kernel, thread count and OpenCL device offline
+    Bench {
+        #[structopt(flatten)]
+        miner_cfg: MinerConfig,
+
+        /// Seconds to run each miner before measuring it
+        #[structopt(long, default_value = "3")]
+        warmup: f64,
+
+        /// Seconds to measure each miner for
+        #[structopt(long, default_value = "10")]
+        duration: f64,
+
+        /// The work of the synthetic target. The default of 0 is practically
+        /// never solved, while higher values exercise the solution path.
+        #[structopt(long, default_value = "0")]
+        work: u64,
+
+        /// Print the results as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Estimate mining results for a given hashrate and work value
+    Estimate {
+        /// The combined hashrate, e.g. `1.5M` or `250 kh/s`
+        #[structopt(long, parse(try_from_str = parse_hashrate))]
+        hashrate: f64,
+
+        /// The work value to estimate for
+        #[structopt(long)]
+        work: u64,
+
+        /// The block value in KST, used to estimate earnings
+        #[structopt(long)]
+        value: Option<u32>,
     },
 }
 
@@ -57,7 +173,7 @@ async fn net_log(net_cfg: NetConfig) -> Result<(), NetworkError> {
 }
 
 fn system_info() {
-    match miner::gpu::get_opencl_devices() {
+    match miner::gpu::get_opencl_devices(DeviceKinds::all()) {
         Ok(devices) => {
             for d in devices {
                 println!("{}", d)
@@ -71,58 +187,407 @@ fn system_info() {
     println!("{}", miner::cpu::get_cpu_info());
 }
 
+fn print_estimate(hashrate: f64, work: u64, value: Option<u32>) {
+    let estimate = Estimate::new(hashrate, work);
+
+    println!(
+        "At {} and work {} (success chance per hash: {:e}):",
+        format_hashrate(hashrate),
+        work,
+        estimate::hash_probability(work)
+    );
+
+    match estimate.expected_block_time() {
+        Some(t) => println!(
+            "\tExpected time per block: {}",
+            estimate::format_duration(t)
+        ),
+        None => println!("\tExpected time per block: never"),
+    }
+
+    println!(
+        "\tChance of a block within an hour: {:.2}%",
+        estimate.chance_within(Duration::from_secs(3600)) * 100.0
+    );
+
+    println!(
+        "\tExpected blocks per day: {:.2}",
+        estimate.blocks_per_second() * 86400.0
+    );
+
+    if let Some(value) = value {
+        println!(
+            "\tExpected earnings: {:.2} KST/day",
+            estimate.kst_per_day(value)
+        );
+    }
+}
+
+/// Periodically check the owner of `name`, warning (and pausing, depending on
+/// the policy) while it isn't `owner`
+async fn watch_name(
+    net_cfg: NetConfig,
+    name: Name,
+    owner: Address,
+    name_cfg: NameConfig,
+    pause: Pause,
+    pb: ProgressBar,
+) -> Result<(), Box<dyn Error>> {
+    let mut changed = false;
+
+    loop {
+        tokio::time::delay_for(Duration::from_secs(name_cfg.name_refresh)).await;
+
+        let current = match network::get_name(&net_cfg, &name).await {
+            Ok(info) => info.owner,
+            Err(e) => {
+                log::warn!("Error checking owner of {}: {}", name, e);
+                continue;
+            }
+        };
+
+        if (current != owner) == changed {
+            continue;
+        }
+
+        changed = current != owner;
+
+        let message = if changed {
+            format!(
+                "WARNING: {} is now owned by {}, not {}{}",
+                name,
+                current,
+                owner,
+                match name_cfg.on_owner_change {
+                    OwnerChangePolicy::Warn => " - still mining for the original owner",
+                    OwnerChangePolicy::Pause => " - pausing until it changes back",
+                }
+            )
+        } else {
+            format!("{} is owned by {} again", name, owner)
+        };
+
+        log::warn!("{}", message);
+        pb.println(&message);
+
+        if name_cfg.on_owner_change == OwnerChangePolicy::Pause {
+            pause.set(PauseReason::NameOwnerChanged, changed);
+        }
+    }
+}
+
+/// Periodically read the system load, pausing mining while other processes
+/// are using more than `threshold` (from 0 to 1) of the CPU
+async fn watch_load(
+    mut reader: impl LoadReader,
+    threshold: f64,
+    idle_cfg: IdleConfig,
+    pause: Pause,
+    pb: ProgressBar,
+) -> Result<(), Box<dyn Error>> {
+    let mut monitor = IdleMonitor::new(threshold, Duration::from_secs(idle_cfg.idle_cooldown));
+
+    loop {
+        tokio::time::delay_for(Duration::from_secs(idle_cfg.idle_interval)).await;
+
+        let load = match reader.other_load() {
+            Ok(load) => load,
+            Err(e) => {
+                log::warn!("Error reading system load: {}", e);
+                continue;
+            }
+        };
+
+        let was_paused = monitor.is_paused();
+        let paused = monitor.update(load, std::time::Instant::now());
+
+        if paused != was_paused {
+            let message = if paused {
+                format!(
+                    "Other processes are using {:.0}% of the CPU - pausing until the system is idle",
+                    load * 100.
+                )
+            } else {
+                "The system is idle - resuming mining".to_string()
+            };
+
+            log::info!("{}", message);
+            pb.println(&message);
+            pause.set(PauseReason::SystemBusy, paused);
+        }
+    }
+}
+
+/// Pause mining whenever it's outside of `schedule`, checking every minute
+async fn watch_schedule(
+    schedule: Schedule,
+    clock: Box<dyn Clock + Send>,
+    pause: Pause,
+    pb: ProgressBar,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let active = schedule.is_active_now(clock.as_ref());
+
+        if pause.set(PauseReason::Schedule, !active) {
+            let message = if active {
+                "Mining schedule started - resuming"
+            } else {
+                "Outside the mining schedule - pausing"
+            };
+
+            log::info!("{} ({})", message, schedule);
+            pb.println(message);
+        }
+
+        tokio::time::delay_for(Duration::from_secs(60)).await;
+    }
+}
+
+/// Get the configurations to benchmark: every supported (or the selected)
+/// CPU kernel with one thread and all threads (or the selected thread count),
+/// followed by the OpenCL devices
+fn bench_configs(miner_cfg: &MinerConfig) -> Vec<MinerConfig> {
+    let mut configs = vec![];
+
+    if !miner_cfg.no_cpu {
+        let kernels = match miner_cfg.cpu_kernel {
+            Some(kernel) => kernel.into(),
+            None => miner::cpu::get_cpu_info().supported_kernels(),
+        };
+
+        let threads = match miner_cfg.cpu_threads {
+            Some(threads) => vec![threads],
+            None => vec![1, num_cpus::get()].into_iter().dedup().collect(),
+        };
+
+        for (kernel, threads) in kernels.iter().cartesian_product(threads) {
+            configs.push(MinerConfig {
+                no_gpu: true,
+                cpu_kernel: Some(kernel),
+                cpu_threads: Some(threads),
+                ..miner_cfg.clone()
+            });
+        }
+    }
+
+    if !miner_cfg.no_gpu {
+        configs.push(MinerConfig {
+            no_cpu: true,
+            ..miner_cfg.clone()
+        });
+    }
+
+    configs
+}
+
+fn run_bench(
+    miner_cfg: MinerConfig,
+    config: BenchConfig,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut results: Vec<BenchResult> = vec![];
+
+    for miner_cfg in bench_configs(&miner_cfg) {
+        for miner in miner::create_miners(miner_cfg)? {
+            eprintln!("Benchmarking {}...", miner.describe());
+            results.push(bench::bench(miner, &config)?);
+        }
+    }
+
+    if json {
+        let output = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "warmup": config.warmup.as_secs_f64(),
+            "duration": config.duration.as_secs_f64(),
+            "work": config.work,
+            "results": results,
+        });
+
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        println!(
+            "{:<40} {:>14} {:>14} {:>8} {:>12}",
+            "Miner", "Hashrate", "Std. dev.", "Samples", "Solutions/s"
+        );
+
+        for r in &results {
+            println!(
+                "{:<40} {:>14} {:>14} {:>8} {:>12.2}",
+                r.miner,
+                format_hashrate(r.hashrate),
+                format_hashrate(r.std_dev),
+                r.samples,
+                r.solutions_per_second
+            );
+        }
+    }
+
+    Ok(())
+}
+
 async fn mine(
     net_cfg: NetConfig,
-    address: Address,
+    recipient: Recipient,
     miner_cfg: MinerConfig,
+    name_cfg: NameConfig,
+    idle_cfg: IdleConfig,
+    schedule_cfg: ScheduleConfig,
+    session_cfg: SessionConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let miners = miner::create_miners(miner_cfg)?;
+    let (address, mining_for) = match &recipient {
+        Recipient::Address(address) => (*address, address.to_string()),
+        Recipient::Name(name) => {
+            let info = network::get_name(&net_cfg, name).await?;
+            log::info!("Resolved {} to {}", name, info.owner);
+            (info.owner, format!("{} ({})", name, info.owner))
+        }
+    };
+
+    let (mut factories, warnings) = miner::create_miner_factories_with_warnings(miner_cfg)?;
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+        log::warn!("{}", warning);
+    }
+
+    // miners that fail to start are created again by their supervisor, which
+    // reports the error and backs off, rather than stopping the other miners
+    let miners: Vec<_> = factories.iter_mut().map(|factory| factory().ok()).collect();
 
     if miners.is_empty() {
         eprintln!("No miners available!");
         return Ok(());
     }
 
-    let mut target_channels = vec![];
+    let (target_tx, target_rx) = watch::channel();
     let (sol_tx, sol_rx) = futures::channel::mpsc::unbounded();
 
     let multi_pb = MultiProgress::new();
 
     let wallet_pb = multi_pb.add(ProgressBar::new_spinner());
     wallet_pb.set_style(ProgressStyle::default_bar().template("{wide_msg}"));
-    wallet_pb.set_message(&format!("Mining for {}", address));
+    wallet_pb.set_message(&format!("Mining for {}", mining_for));
     let mut mined_kst = 0;
 
     let target_pb = multi_pb.add(ProgressBar::new_spinner());
     target_pb.set_style(ProgressStyle::default_spinner().template("Current target: {wide_msg}"));
 
+    let estimate_pb = multi_pb.add(ProgressBar::new_spinner());
+    estimate_pb.set_style(ProgressStyle::default_spinner().template("Estimate: {wide_msg}"));
+    let current_target = Arc::new(AtomicCell::new(None));
+
     let miner_style = ProgressStyle::default_spinner().template("{spinner} {prefix}: {wide_msg}");
 
-    for miner in miners {
-        let (target_tx, target_rx) = crossbeam::channel::bounded(1);
-        target_channels.push(target_tx);
+    let bus = EventBus::new();
+    let mut bars = HashMap::new();
+    let mut thread_bars = vec![];
+
+    let log_rx = bus.subscribe();
+    std::thread::spawn(move || miner_events::log_events(log_rx));
 
-        let name = miner.describe();
+    let ui_rx = bus.subscribe();
+    let pause = Pause::new();
+
+    // progress is only useful when mining for the same address again
+    let nonces = match kristforge::data_dir() {
+        Some(dir) if !session_cfg.no_save_nonces => {
+            NonceAllocator::persistent(dir.join(format!("nonces-{}.json", address)))
+        }
+        _ => NonceAllocator::new(),
+    };
+
+    for (i, (factory, miner)) in factories.into_iter().zip(miners).enumerate() {
+        let id = MinerId(i);
         let pb = multi_pb.add(ProgressBar::new_spinner());
-        pb.set_prefix(&name);
+        pb.set_prefix(&miner.as_ref().map_or(id.to_string(), |m| m.describe()));
         pb.set_style(miner_style.clone());
-        pb.set_message("Initializing...");
+        bars.insert(id, pb);
+
+        let threads = miner.as_ref().map_or(1, |m| m.threads());
+        if session_cfg.show_threads && threads > 1 {
+            let rows = (0..threads)
+                .map(|t| {
+                    let pb = multi_pb.add(ProgressBar::new_spinner());
+                    pb.set_prefix(&format!("  thread #{}", t));
+                    pb.set_style(miner_style.clone());
+                    pb
+                })
+                .collect();
+            thread_bars.push((id, rows));
+        }
 
-        let interface = MinerInterface::new(address, pb, target_rx, sol_tx.clone());
+        let interface =
+            MinerInterface::new(address, bus.emitter(id), target_rx.clone(), sol_tx.clone())
+                .with_pause(pause.clone())
+                .with_nonces(nonces.clone());
 
-        std::thread::spawn(move || {
-            miner.mine(interface).unwrap();
-        });
+        let supervisor_cfg = session_cfg.supervisor_cfg.clone();
+        std::thread::spawn(move || supervise(factory, miner, interface, &supervisor_cfg));
     }
 
+    // sending targets fails once every miner has stopped for good
+    drop(target_rx);
+
+    thread_bars
+        .into_iter()
+        .fold(
+            MinerUi::new(bars, estimate_pb, current_target.clone()),
+            |ui, (id, rows)| ui.with_thread_bars(id, rows),
+        )
+        .spawn(ui_rx);
     std::thread::spawn(move || multi_pb.join().unwrap());
 
+    let name_watcher = match recipient {
+        Recipient::Name(name) => future::Either::Left(watch_name(
+            net_cfg.clone(),
+            name,
+            address,
+            name_cfg,
+            pause.clone(),
+            wallet_pb.clone(),
+        )),
+        Recipient::Address(_) => future::Either::Right(future::pending()),
+    };
+
+    let load_reader = idle_cfg
+        .pause_when_busy
+        .and_then(|threshold| match ProcLoadReader::new() {
+            Ok(reader) => Some((reader, threshold.fraction())),
+            Err(e) => {
+                log::warn!("Can't read system load, mining regardless: {}", e);
+                None
+            }
+        });
+
+    let load_watcher = match load_reader {
+        Some((reader, threshold)) => future::Either::Left(watch_load(
+            reader,
+            threshold,
+            idle_cfg,
+            pause.clone(),
+            wallet_pb.clone(),
+        )),
+        None => future::Either::Right(future::pending()),
+    };
+
+    let schedule_watcher = match schedule_cfg.schedule {
+        Some(schedule) => {
+            let clock: Box<dyn Clock + Send> = if schedule_cfg.schedule_utc {
+                Box::new(UtcClock)
+            } else {
+                Box::new(LocalClock)
+            };
+
+            future::Either::Left(watch_schedule(schedule, clock, pause, wallet_pb.clone()))
+        }
+        None => future::Either::Right(future::pending()),
+    };
+
     // set up network connection
     let (sink, stream) = network::connect(net_cfg).await?;
 
     // set up futures to pipe messages
     let solution_sender = sol_rx
-        .map(|n| Ok(ClientMessage::new_solution(address, n)))
+        .map(|s| Ok(ClientMessage::new_solution(s.address, s.nonce)))
         .forward(sink)
         .err_into::<Box<dyn Error>>();
 
@@ -146,35 +611,34 @@ async fn mine(
 
             if address == block.address && msg_type == "response" {
                 mined_kst += block.value;
-                wallet_pb.set_message(&format!("Mined {} KST for {}", mined_kst, address));
+                wallet_pb.set_message(&format!("Mined {} KST for {}", mined_kst, mining_for));
             }
 
             target_pb.set_message(&format!(
                 "Block #{} (shorthash {}, work {})",
                 block.height, block.short_hash, work
             ));
+            current_target.store(Some((work, block.value)));
 
-            for tx in &target_channels {
-                if let Err(e) = tx.send(Target {
-                    block: block.short_hash,
-                    work,
-                }) {
-                    return future::err(e.into());
-                }
+            // never blocks, so a busy miner can't hold up the connection
+            match target_tx.send(Target {
+                block: block.short_hash,
+                work,
+            }) {
+                Ok(()) => future::ok(()),
+                Err(e) => future::err(e.into()),
             }
-
-            future::ok(())
         }
     });
 
-    future::try_join(solution_sender, target_receiver).await?;
+    let watchers = future::try_join3(name_watcher, load_watcher, schedule_watcher);
+    future::try_join3(solution_sender, target_receiver, watchers).await?;
 
     Ok(())
 }
 
 fn init_logging() {
-    let log_file = dirs::data_dir()
-        .map(|d| d.join(env!("CARGO_PKG_NAME")))
+    let log_file = kristforge::data_dir()
         .unwrap_or_default()
         .join(concat!(env!("CARGO_PKG_NAME"), ".log"));
 
@@ -204,12 +668,48 @@ async fn main() {
             }
         }
         Opts::Info {} => system_info(),
+        Opts::Estimate {
+            hashrate,
+            work,
+            value,
+        } => print_estimate(hashrate, work, value),
+        Opts::Bench {
+            miner_cfg,
+            warmup,
+            duration,
+            work,
+            json,
+        } => {
+            let config = BenchConfig {
+                warmup: Duration::from_secs_f64(warmup),
+                duration: Duration::from_secs_f64(duration),
+                work,
+            };
+
+            if let Err(e) = run_bench(miner_cfg, config, json) {
+                eprintln!("Benchmark error: {:?}", e);
+            }
+        }
         Opts::Mine {
             net_cfg,
             address,
             miner_cfg,
+            name_cfg,
+            idle_cfg,
+            schedule_cfg,
+            session_cfg,
         } => {
-            if let Err(e) = mine(net_cfg, address, miner_cfg).await {
+            if let Err(e) = mine(
+                net_cfg,
+                address,
+                miner_cfg,
+                name_cfg,
+                idle_cfg,
+                schedule_cfg,
+                session_cfg,
+            )
+            .await
+            {
                 eprintln!("Mining error: {:?}", e);
             }
         }
@@ -0,0 +1,237 @@
+//! Measuring miner performance offline, using synthetic targets
+
+use super::events::{Event, EventBus, EventKind, MinerId};
+use super::interface::MinerInterface;
+use super::{Miner, MinerError, Target};
+use crate::krist::address::Address;
+use crate::krist::block::ShortHash;
+use crossbeam::channel::RecvTimeoutError;
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Configuration for benchmarking a miner
+#[derive(Debug, Clone, Copy)]
+pub struct BenchConfig {
+    /// How long to run the miner before measuring it
+    pub warmup: Duration,
+
+    /// How long to measure the miner for
+    pub duration: Duration,
+
+    /// The work of the synthetic target. A work of 0 is practically never
+    /// solved, while higher values exercise the solution path.
+    pub work: u64,
+}
+
+/// The measured performance of a miner
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchResult {
+    /// The description of the miner
+    pub miner: String,
+
+    /// The mean hashrate over the measured period, in hashes per second
+    pub hashrate: f64,
+
+    /// The standard deviation of the hashrate reported by the miner, weighted
+    /// by the duration of each sample
+    pub std_dev: f64,
+
+    /// The number of hashrate samples reported by the miner
+    pub samples: usize,
+
+    /// The number of solutions found per second
+    pub solutions_per_second: f64,
+}
+
+/// Run a miner against a synthetic target, returning its performance once
+/// the warm-up and measured periods have passed. Miners which calibrate when
+/// they start are only warmed up and measured once they're done.
+pub fn bench(
+    miner: Box<dyn Miner + Send>,
+    config: &BenchConfig,
+) -> Result<BenchResult, MinerError> {
+    let mut description = miner.describe();
+    let bus = EventBus::new();
+    let events = bus.subscribe();
+
+    let (target_tx, target_rx) = super::watch::channel();
+    let (sol_tx, sol_rx) = futures::channel::mpsc::unbounded();
+    let address = Address::from_str("kristforge").unwrap();
+    let interface = MinerInterface::new(address, bus.emitter(MinerId(0)), target_rx, sol_tx);
+
+    let handle = std::thread::spawn(move || super::run(miner, interface));
+
+    // the miner may have already failed, which is reported when joining
+    let _ = target_tx.send(Target {
+        work: config.work,
+        block: ShortHash::from_str("000000000000").unwrap(),
+    });
+
+    let mut measure_start = Instant::now() + config.warmup;
+    let mut calibrating = false;
+    let mut samples = vec![];
+    let mut solutions = 0;
+
+    loop {
+        // calibration may take any amount of time, so only the warm-up and
+        // measured periods after it are timed
+        let event = if calibrating {
+            events.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            let end = measure_start + config.duration;
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+
+            events.recv_timeout(end - now)
+        };
+
+        match event {
+            // only count samples taken entirely after the warm-up
+            Ok(Event { time, kind, .. }) => match kind {
+                EventKind::Calibrating => calibrating = true,
+                EventKind::Initializing { description: d } => {
+                    description = d;
+                    if calibrating {
+                        calibrating = false;
+                        measure_start = Instant::now() + config.warmup;
+                    }
+                }
+                EventKind::Hashrate { hashes, elapsed }
+                    if elapsed > Duration::from_secs(0)
+                        && time.checked_sub(elapsed) >= Some(measure_start) =>
+                {
+                    samples.push((hashes, elapsed))
+                }
+                EventKind::Solution { .. } if time >= measure_start => solutions += 1,
+                EventKind::Error(_) | EventKind::Stopped => break,
+                _ => {}
+            },
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    // stop the miner
+    drop(target_tx);
+    handle
+        .join()
+        .unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+    drop(sol_rx);
+
+    let total_hashes: u64 = samples.iter().map(|&(h, _)| h).sum();
+    let total_time: f64 = samples.iter().map(|&(_, t)| t.as_secs_f64()).sum();
+    let hashrate = if total_time > 0. {
+        total_hashes as f64 / total_time
+    } else {
+        0.
+    };
+
+    // weight samples by their duration, since miners may report at irregular
+    // intervals (e.g. whenever a solution is found)
+    let variance = if total_time > 0. {
+        samples
+            .iter()
+            .map(|&(h, t)| {
+                let t = t.as_secs_f64();
+                t * (h as f64 / t - hashrate).powi(2)
+            })
+            .sum::<f64>()
+            / total_time
+    } else {
+        0.
+    };
+
+    Ok(BenchResult {
+        miner: description,
+        hashrate,
+        std_dev: variance.sqrt(),
+        samples: samples.len(),
+        solutions_per_second: solutions as f64 / config.duration.as_secs_f64(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner::cpu::KernelType;
+    use crate::miner::interface::CurrentTarget;
+    use crate::miner::{create_miners, MinerConfig};
+
+    fn cpu_miner() -> Box<dyn Miner + Send> {
+        let config = MinerConfig {
+            no_gpu: true,
+            cpu_threads: Some(1),
+            cpu_kernel: Some(KernelType::Portable),
+            ..MinerConfig::default()
+        };
+
+        create_miners(config).unwrap().pop().unwrap()
+    }
+
+    #[test]
+    fn test_bench_cpu() {
+        let config = BenchConfig {
+            warmup: Duration::from_millis(100),
+            duration: Duration::from_millis(2500),
+            work: 0,
+        };
+
+        let result = bench(cpu_miner(), &config).unwrap();
+        assert!(result.samples > 0);
+        assert!(result.hashrate > 0.);
+        assert_eq!(result.solutions_per_second, 0.);
+    }
+
+    #[test]
+    fn test_bench_solutions() {
+        let config = BenchConfig {
+            warmup: Duration::from_millis(0),
+            duration: Duration::from_millis(500),
+            work: u64::MAX,
+        };
+
+        let result = bench(cpu_miner(), &config).unwrap();
+        assert!(result.solutions_per_second > 0.);
+    }
+
+    /// A miner which takes longer to calibrate than the whole benchmark
+    struct SlowCalibration;
+
+    impl Miner for SlowCalibration {
+        fn describe(&self) -> String {
+            "slow [auto]".to_string()
+        }
+
+        fn mine(self: Box<Self>, mut interface: MinerInterface) -> Result<(), MinerError> {
+            interface.events().emit(EventKind::Calibrating);
+            std::thread::sleep(Duration::from_millis(300));
+            interface.events().emit(EventKind::Initializing {
+                description: "slow [calibrated]".to_string(),
+            });
+
+            while let CurrentTarget::New(_) | CurrentTarget::Unchanged(_) =
+                interface.wait_for_target()
+            {
+                std::thread::sleep(Duration::from_millis(10));
+                interface.report_speed(1000, Duration::from_millis(10));
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bench_after_calibration() {
+        let config = BenchConfig {
+            warmup: Duration::from_millis(50),
+            duration: Duration::from_millis(200),
+            work: 0,
+        };
+
+        let result = bench(Box::new(SlowCalibration), &config).unwrap();
+        assert_eq!(result.miner, "slow [calibrated]");
+        assert!(result.samples > 0);
+    }
+}
@@ -0,0 +1,165 @@
+//! Measurements cached in the data directory, such as the fastest CPU kernel
+//! or OpenCL kernel configuration for some hardware
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Held while a cache file is read and written, since miners measure their
+/// hardware on their own threads at the same time
+static CACHE_LOCK: Mutex<()> = Mutex::new(());
+
+/// A cached measurement, tagged with the version of kristforge which took it
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry<T> {
+    version: String,
+    value: T,
+}
+
+/// Cached measurements, keyed by hardware. Entries are kept as JSON values so
+/// that ones which can't be parsed by this version are preserved.
+type Cache = HashMap<String, Value>;
+
+fn load(path: &Path) -> Cache {
+    File::open(path)
+        .ok()
+        .and_then(|f| match serde_json::from_reader(f) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                log::warn!("Ignoring invalid cache {}: {}", path.display(), e);
+                None
+            }
+        })
+        .unwrap_or_default()
+}
+
+fn save(path: &Path, cache: &Cache) {
+    let saved = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| File::create(path))
+        .map_err(serde_json::Error::io)
+        .and_then(|f| serde_json::to_writer_pretty(f, cache));
+
+    if let Err(e) = saved {
+        log::warn!("Error saving cache {}: {}", path.display(), e);
+    }
+}
+
+/// Get the measurement cached in `path` under `key`, if it was taken by this
+/// version of kristforge and `is_valid` accepts it
+pub fn get<T: DeserializeOwned>(
+    path: Option<&Path>,
+    key: &str,
+    is_valid: impl FnOnce(&T) -> bool,
+) -> Option<T> {
+    let path = path?;
+    let cached = {
+        let _lock = CACHE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        load(path).remove(key)?
+    };
+
+    serde_json::from_value::<Entry<T>>(cached)
+        .ok()
+        .filter(|e| e.version == env!("CARGO_PKG_VERSION"))
+        .map(|e| e.value)
+        .filter(is_valid)
+}
+
+/// Get the measurement cached in `path` under `key` like [`get`], or take and
+/// cache a new one with `measure` if there isn't a valid one
+pub fn cached<T, E>(
+    path: Option<&Path>,
+    key: &str,
+    is_valid: impl FnOnce(&T) -> bool,
+    measure: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E>
+where
+    T: Serialize + DeserializeOwned,
+{
+    if let Some(value) = get(path, key, is_valid) {
+        log::info!("Using cached measurements for {}", key);
+        return Ok(value);
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => return measure(),
+    };
+
+    let entry = Entry {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        value: measure()?,
+    };
+
+    // reload the cache in case something else was measured meanwhile
+    let _lock = CACHE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut cache = load(path);
+    match serde_json::to_value(&entry) {
+        Ok(value) => {
+            cache.insert(key.to_string(), value);
+            save(path, &cache);
+        }
+        Err(e) => log::warn!("Error saving cache {}: {}", path.display(), e),
+    }
+
+    Ok(entry.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::convert::Infallible;
+
+    #[test]
+    fn test_cached() {
+        let dir = std::env::temp_dir().join(format!("kristforge-test-{}", rand::random::<u64>()));
+        let path = dir.join("cache.json");
+        let measured = &Cell::new(0);
+
+        let get = |key, min: u32, value: u32| {
+            cached(
+                Some(&path),
+                key,
+                |&v| v >= min,
+                || {
+                    measured.set(measured.get() + 1);
+                    Ok::<_, Infallible>(value)
+                },
+            )
+            .unwrap()
+        };
+
+        // first run measures, later runs with the same key reuse the result
+        assert_eq!(get("a", 0, 4), 4);
+        assert_eq!(get("a", 0, 8), 4);
+        assert_eq!(measured.get(), 1);
+
+        // other keys are measured separately
+        assert_eq!(get("b", 0, 2), 2);
+        assert_eq!(get("a", 0, 8), 4);
+        assert_eq!(measured.get(), 2);
+
+        // results rejected by the caller are measured again
+        assert_eq!(get("a", 5, 8), 8);
+        assert_eq!(measured.get(), 3);
+
+        // results from another version are measured again
+        let mut cache = load(&path);
+        cache.insert(
+            "b".to_string(),
+            serde_json::json!({"version": "0.0.0", "value": 2}),
+        );
+        save(&path, &cache);
+
+        assert_eq!(get("b", 0, 1), 1);
+        assert_eq!(measured.get(), 4);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
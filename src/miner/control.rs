@@ -0,0 +1,289 @@
+//! Controlling running miners from outside their threads
+//!
+//! Every [`MinerInterface`](super::interface::MinerInterface) has a
+//! [`MinerControl`] handle, which tools and UIs can use to pause, resume,
+//! reconfigure and inspect the miner while it runs:
+//!
+//! - Pausing and resuming is handled by the interface, so works the same for
+//!   every miner.
+//! - Stats are recorded by the interface as the miner reports its progress.
+//! - Reconfiguration requests are queued for the miner, which takes them with
+//!   [`MinerInterface::pending_change`](super::interface::MinerInterface::pending_change)
+//!   and must reply to each one. Miners reject changes which don't apply to
+//!   them with [`ControlError::Unsupported`].
+
+use super::cpu::KernelType;
+use super::pause::{Pause, PauseReason};
+use crossbeam::channel::{RecvTimeoutError, Sender};
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A change to the configuration of a running miner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reconfigure {
+    /// Change the number of CPU mining threads
+    Threads(usize),
+
+    /// Change the target OpenCL kernel execution time, in seconds
+    GpuRate(f32),
+
+    /// Switch to a different CPU kernel, which must be supported by the CPU
+    CpuKernel(KernelType),
+}
+
+impl Display for Reconfigure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Reconfigure::Threads(n) => write!(f, "set threads to {}", n),
+            Reconfigure::GpuRate(r) => write!(f, "set GPU rate to {}s", r),
+            Reconfigure::CpuKernel(k) => write!(f, "set CPU kernel to {}", k),
+        }
+    }
+}
+
+/// A command for a running miner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Pause,
+    Resume,
+    Reconfigure(Reconfigure),
+    Stats,
+}
+
+/// The result of a successful command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Done,
+    Stats(MinerStats),
+}
+
+/// An error caused by a command which couldn't be carried out
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ControlError {
+    #[error("{0} isn't supported by this miner")]
+    Unsupported(Reconfigure),
+
+    #[error("invalid change: {0}")]
+    Invalid(String),
+
+    #[error("the miner didn't respond in time")]
+    Timeout,
+
+    #[error("the miner has stopped")]
+    Stopped,
+}
+
+/// A snapshot of a miner's progress
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinerStats {
+    /// The most recently reported hashrate, in hashes per second
+    pub hashrate: f64,
+
+    /// The number of hashes computed since the miner started
+    pub total_hashes: u64,
+
+    /// The number of solutions found since the miner started
+    pub solutions: u64,
+
+    /// How long the miner has been running for
+    pub uptime: Duration,
+
+    /// Why the miner is paused, if it is
+    pub paused: Vec<PauseReason>,
+}
+
+/// Progress recorded by a miner's interface
+#[derive(Debug)]
+pub(crate) struct Counters {
+    pub hashrate: f64,
+    pub total_hashes: u64,
+    pub solutions: u64,
+    pub started: Instant,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            hashrate: 0.,
+            total_hashes: 0,
+            solutions: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// A reconfiguration request waiting for a miner to apply it
+#[derive(Debug)]
+pub struct PendingChange {
+    change: Reconfigure,
+    reply: Sender<Result<(), ControlError>>,
+}
+
+impl PendingChange {
+    /// The requested change
+    pub fn change(&self) -> Reconfigure {
+        self.change
+    }
+
+    /// Tell the requester whether the change was applied
+    pub fn reply(self, result: Result<(), ControlError>) {
+        match &result {
+            Ok(()) => log::info!("Applied change: {}", self.change),
+            Err(e) => log::warn!("Rejected change: {}", e),
+        }
+
+        // the requester may have given up waiting
+        let _ = self.reply.send(result);
+    }
+
+    /// Reject the change as unsupported by this miner
+    pub fn reject(self) {
+        let change = self.change;
+        self.reply(Err(ControlError::Unsupported(change)));
+    }
+}
+
+/// A handle used to control a running miner. Clones control the same miner.
+#[derive(Debug, Clone)]
+pub struct MinerControl {
+    pub(crate) pause: Pause,
+    pub(crate) session_pause: Pause,
+    pub(crate) counters: Arc<Mutex<Counters>>,
+    pub(crate) changes: Sender<PendingChange>,
+}
+
+impl MinerControl {
+    /// Pause this miner, independently of the rest of the session
+    pub fn pause(&self) {
+        self.pause.pause(PauseReason::Manual);
+    }
+
+    /// Resume this miner after [`pause`](Self::pause). It stays paused if
+    /// the session is paused for other reasons.
+    pub fn resume(&self) {
+        self.pause.resume(PauseReason::Manual);
+    }
+
+    /// Get a snapshot of the miner's progress
+    pub fn stats(&self) -> MinerStats {
+        let counters = self.counters.lock().unwrap();
+        let mut paused = self.session_pause.reasons();
+        paused.extend(self.pause.reasons());
+        paused.sort();
+        paused.dedup();
+
+        MinerStats {
+            hashrate: counters.hashrate,
+            total_hashes: counters.total_hashes,
+            solutions: counters.solutions,
+            uptime: counters.started.elapsed(),
+            paused,
+        }
+    }
+
+    /// Ask the miner to apply a change, waiting up to `timeout` for it to
+    /// reply
+    pub fn reconfigure(&self, change: Reconfigure, timeout: Duration) -> Result<(), ControlError> {
+        let (reply, result) = crossbeam::channel::bounded(1);
+
+        self.changes
+            .send(PendingChange { change, reply })
+            .map_err(|_| ControlError::Stopped)?;
+
+        match result.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(ControlError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(ControlError::Stopped),
+        }
+    }
+
+    /// Carry out a command, waiting up to `timeout` for reconfigurations
+    pub fn execute(&self, command: Command, timeout: Duration) -> Result<Response, ControlError> {
+        match command {
+            Command::Pause => self.pause(),
+            Command::Resume => self.resume(),
+            Command::Reconfigure(change) => self.reconfigure(change, timeout)?,
+            Command::Stats => return Ok(Response::Stats(self.stats())),
+        }
+
+        Ok(Response::Done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::krist::address::Address;
+    use crate::krist::block::ShortHash;
+    use crate::miner::cpu::CpuMiner;
+    use crate::miner::events::{EventBus, EventKind, MinerId};
+    use crate::miner::interface::MinerInterface;
+    use crate::miner::{Miner, MinerConfig, Target};
+    use std::str::FromStr;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_control_cpu_miner() {
+        let (target_tx, target_rx) = crate::miner::watch::channel();
+        let (sol_tx, _sol_rx) = futures::channel::mpsc::unbounded();
+        let address = Address::from_str("k5ztameslf").unwrap();
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+        let interface = MinerInterface::new(address, bus.emitter(MinerId(0)), target_rx, sol_tx);
+        let control = interface.control();
+        let stop = interface.stop_handle();
+
+        target_tx
+            .send(Target {
+                work: 0,
+                block: ShortHash::from_str("000000000000").unwrap(),
+            })
+            .unwrap();
+
+        let miner = CpuMiner::new(&MinerConfig {
+            cpu_threads: Some(1),
+            cpu_kernel: Some(KernelType::Portable),
+            ..MinerConfig::default()
+        })
+        .unwrap();
+        let handle = std::thread::spawn(move || Box::new(miner).mine(interface));
+        let timeout = Duration::from_secs(10);
+
+        assert_eq!(
+            control.reconfigure(Reconfigure::Threads(2), timeout),
+            Ok(())
+        );
+        assert!(matches!(
+            control.reconfigure(Reconfigure::Threads(0), timeout),
+            Err(ControlError::Invalid(_))
+        ));
+        assert_eq!(
+            control.reconfigure(Reconfigure::GpuRate(0.5), timeout),
+            Err(ControlError::Unsupported(Reconfigure::GpuRate(0.5)))
+        );
+
+        // wait for a hashrate report from the new threads, which updates the
+        // counters before it's published
+        std::iter::from_fn(|| events.recv_timeout(timeout).ok())
+            .find(|e| matches!(e.kind, EventKind::Hashrate { hashes, .. } if hashes > 0))
+            .expect("no hashrate reported");
+        let stats = control.stats();
+        assert!(stats.total_hashes > 0);
+        assert!(stats.paused.is_empty());
+
+        control.pause();
+        assert_eq!(control.stats().paused, vec![PauseReason::Manual]);
+        assert_eq!(
+            control.execute(Command::Resume, timeout),
+            Ok(Response::Done)
+        );
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap().unwrap();
+        assert_eq!(
+            control.reconfigure(Reconfigure::Threads(1), timeout),
+            Err(ControlError::Stopped)
+        );
+    }
+}
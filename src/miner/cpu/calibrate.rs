@@ -0,0 +1,133 @@
+//! Choosing the fastest CPU kernel by measuring each one
+
+use super::KernelType;
+use crate::miner::cache;
+use enumset::EnumSet;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The number of hashes each kernel is timed for
+pub const CALIBRATION_HASHES: u64 = 1 << 20;
+
+/// The name of the calibration cache file in the data directory
+const CACHE_FILE: &str = "cpu-kernels.json";
+
+/// The result of measuring the kernels on a CPU
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// The fastest kernel
+    pub kernel: KernelType,
+
+    /// The measured single-thread hashrate of each kernel
+    pub hashrates: BTreeMap<KernelType, f64>,
+}
+
+impl Calibration {
+    /// Time each of the given kernels on the current thread
+    pub fn measure(kernels: EnumSet<KernelType>) -> Self {
+        let hashrates: BTreeMap<_, _> = kernels
+            .iter()
+            .map(|k| {
+                // warm up caches and clock speeds before timing
+                k.time(CALIBRATION_HASHES / 8);
+                let time = k.time(CALIBRATION_HASHES);
+                (k, CALIBRATION_HASHES as f64 / time.as_secs_f64())
+            })
+            .collect();
+
+        let kernel = hashrates
+            .iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(&k, _)| k)
+            .unwrap_or_default();
+
+        Self { kernel, hashrates }
+    }
+
+    /// Whether this calibration can be reused on a CPU supporting the given
+    /// kernels
+    fn is_valid_for(&self, supported: EnumSet<KernelType>) -> bool {
+        self.hashrates.keys().copied().collect::<EnumSet<_>>() == supported
+    }
+}
+
+/// Get the CPU model name, used to tell whether cached calibrations are
+/// still valid
+pub fn cpu_model() -> String {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::x86_64::__cpuid;
+
+        // the brand string is stored in three extended leaves, if supported
+        if __cpuid(0x8000_0000).eax >= 0x8000_0004 {
+            let brand: Vec<u8> = (0x8000_0002..=0x8000_0004)
+                .map(__cpuid)
+                .flat_map(|r| vec![r.eax, r.ebx, r.ecx, r.edx])
+                .flat_map(|r| r.to_le_bytes())
+                .collect();
+
+            let brand = String::from_utf8_lossy(&brand);
+            let brand = brand.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            if !brand.is_empty() {
+                return brand.to_string();
+            }
+        }
+    }
+
+    format!("unknown {} CPU", std::env::consts::ARCH)
+}
+
+/// Find the fastest supported kernel, reusing the calibration cached in
+/// `cache_path` for this CPU if it's still valid, or measuring and caching a
+/// new one otherwise
+pub fn fastest_kernel(
+    cache_path: Option<&Path>,
+    cpu: &str,
+    supported: EnumSet<KernelType>,
+    measure: impl FnOnce(EnumSet<KernelType>) -> Calibration,
+) -> KernelType {
+    let calibration = cache::cached(
+        cache_path,
+        cpu,
+        |c: &Calibration| c.is_valid_for(supported),
+        || {
+            let calibration = measure(supported);
+            log::info!(
+                "Measured CPU kernels for {}: {}",
+                cpu,
+                calibration
+                    .hashrates
+                    .iter()
+                    .map(|(k, h)| format!("{} {:.2} Mh/s", k, h / 1_000_000.))
+                    .join(", ")
+            );
+            Ok::<_, Infallible>(calibration)
+        },
+    );
+
+    let Ok(calibration) = calibration;
+    log::info!("Using CPU kernel {} for {}", calibration.kernel, cpu);
+    calibration.kernel
+}
+
+/// Find the fastest kernel this CPU supports, measuring them if they haven't
+/// been measured on this CPU and version before. The result is reused for
+/// the rest of the process, such as when a miner is restarted.
+pub fn fastest_supported_kernel() -> KernelType {
+    static FASTEST: OnceLock<KernelType> = OnceLock::new();
+
+    *FASTEST.get_or_init(|| {
+        let cache_path = crate::data_dir().map(|d| d.join(CACHE_FILE));
+
+        fastest_kernel(
+            cache_path.as_deref(),
+            &cpu_model(),
+            super::get_supported_kernels(),
+            Calibration::measure,
+        )
+    })
+}
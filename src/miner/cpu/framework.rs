@@ -1,25 +1,40 @@
 //! CPU miner core framework
 
+use super::sha256::{HighNonceTerms, Midstate};
 use crate::krist::address::Address;
 use crate::krist::block::ShortHash;
+use crate::krist::nonce::Nonce;
+use crate::miner::interface::CurrentTarget;
+use crate::miner::nonces::NonceAllocator;
+use crate::miner::throttle::UsageLimit;
+use crate::miner::watch::Epoch;
 use crossbeam::atomic::AtomicCell;
 use crossbeam::channel::Sender;
-use std::str;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::convert::TryInto;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 /// A type that can be used to efficiently feed input to a CPU miner kernel
 pub trait KernelInput: Sized {
+    /// The number of nonces hashed by each call to [`Kernel::score`]
+    const LANES: u64 = 1;
+
     /// Create a new instance with the given address and nonce
     fn new(address: Address, nonce: u64) -> Self;
 
-    /// Set the block
+    /// Set the block, precomputing anything which only depends on the block
+    /// and address
     fn set_block(&mut self, block: &[u8; 12]);
 
+    /// Set the work, for inputs whose kernels compare scores themselves
+    fn set_work(&mut self, _work: u64) {}
+
     /// Increment the nonce for the next cycle
     fn increment_nonce(&mut self);
 
     type Score;
-    fn get_solution(&mut self, work: u64, score: Self::Score) -> Option<String>;
+    fn get_solution(&mut self, work: u64, score: Self::Score) -> Option<Nonce>;
 }
 
 /// A type to manage miner digest input for scalar kernels
@@ -27,6 +42,8 @@ pub trait KernelInput: Sized {
 pub struct ScalarKernelInput {
     data: [u8; 64],
     nonce: u64,
+    midstate: Midstate,
+    high_terms: HighNonceTerms,
 }
 
 impl KernelInput for ScalarKernelInput {
@@ -40,30 +57,38 @@ impl KernelInput for ScalarKernelInput {
         data[62] = (((Self::LENGTH * 8) >> 8) & 0xFF) as u8;
         data[63] = ((Self::LENGTH * 8) & 0xFF) as u8;
 
-        let mut input = Self { data, nonce };
-        input.increment_nonce();
+        let mut input = Self {
+            data,
+            nonce,
+            midstate: Midstate::default(),
+            high_terms: HighNonceTerms::default(),
+        };
+        input.write_nonce();
         input
     }
 
     fn set_block(&mut self, block: &[u8; 12]) {
         self.data[Address::LENGTH..Self::LENGTH - Self::NONCE_LENGTH].copy_from_slice(block);
+        self.midstate = Midstate::new(&self.words());
+        self.high_terms = HighNonceTerms::new(&self.words(), &self.midstate);
     }
 
     fn increment_nonce(&mut self) {
         self.nonce = self.nonce.wrapping_add(1);
-        let n = self.nonce;
+        self.write_nonce();
 
-        for (i, v) in self.nonce_mut().iter_mut().enumerate() {
-            *v = (((n >> (i * 6)) & 0x3f) + 32) as u8;
+        // the high nonce characters only change when the low bits wrap
+        if self.nonce & ((1 << Self::HIGH_NONCE_SHIFT) - 1) == 0 {
+            self.high_terms = HighNonceTerms::new(&self.words(), &self.midstate);
         }
     }
 
     type Score = u64;
 
     #[inline(always)]
-    fn get_solution(&mut self, work: u64, score: u64) -> Option<String> {
+    fn get_solution(&mut self, work: u64, score: u64) -> Option<Nonce> {
         if score <= work {
-            Some(self.nonce_str().to_string())
+            Some(self.nonce())
         } else {
             None
         }
@@ -71,23 +96,40 @@ impl KernelInput for ScalarKernelInput {
 }
 
 impl ScalarKernelInput {
-    const NONCE_LENGTH: usize = 11;
+    const NONCE_LENGTH: usize = Nonce::LENGTH;
     pub const LENGTH: usize = Address::LENGTH + (ShortHash::LENGTH * 2) + Self::NONCE_LENGTH;
 
-    fn nonce_mut(&mut self) -> &mut [u8] {
-        &mut self.data[Self::LENGTH - Self::NONCE_LENGTH..Self::LENGTH]
+    /// The nonce counter bits below the characters in message word 7
+    const HIGH_NONCE_SHIFT: usize =
+        (7 * 4 - (Self::LENGTH - Self::NONCE_LENGTH)) * Nonce::BITS_PER_CHAR;
+
+    /// Get the padded data block as big-endian message words
+    fn words(&self) -> [u32; 16] {
+        let mut words = [0; 16];
+        for (w, bytes) in words.iter_mut().zip(self.data.chunks_exact(4)) {
+            *w = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        words
     }
 
-    /// Get an immutable reference to the expanded nonce (e.g. for submission)
-    pub fn nonce(&mut self) -> &[u8] {
-        self.nonce_mut() as &_
+    fn write_nonce(&mut self) {
+        let n = self.nonce;
+
+        for (i, v) in self.data[Self::LENGTH - Self::NONCE_LENGTH..Self::LENGTH]
+            .iter_mut()
+            .enumerate()
+        {
+            *v = Nonce::encode_char(n, i);
+        }
     }
 
-    pub fn nonce_str(&mut self) -> &str {
-        str::from_utf8(self.nonce()).unwrap()
+    /// Get the current nonce (e.g. for submission)
+    pub fn nonce(&self) -> Nonce {
+        Nonce::from_counter(self.nonce)
     }
 
     /// Get the data for this input
+    #[cfg(test)]
     pub fn data(&self) -> &[u8] {
         &self.data[..Self::LENGTH]
     }
@@ -96,6 +138,151 @@ impl ScalarKernelInput {
     pub fn data_block(&self) -> &[u8; 64] {
         &self.data
     }
+
+    /// Get the state precomputed by [`set_block`](KernelInput::set_block)
+    pub fn midstate(&self) -> &Midstate {
+        &self.midstate
+    }
+
+    /// Get the message schedule terms precomputed from the high nonce
+    /// characters
+    pub fn high_terms(&self) -> &HighNonceTerms {
+        &self.high_terms
+    }
+}
+
+/// A type to manage miner digest input for kernels hashing `N` nonces at once.
+///
+/// The padded data block is stored as big-endian message words, interleaved
+/// so that `words[w][lane]` is word `w` of the block for `lane`. Lane `i`
+/// hashes the nonce `nonce + i`.
+#[derive(Clone)]
+pub struct VectorKernelInput<const N: usize> {
+    words: [[u32; N]; 16],
+    nonce: u64,
+    work: u64,
+    midstate: Midstate,
+}
+
+impl<const N: usize> KernelInput for VectorKernelInput<N> {
+    const LANES: u64 = N as u64;
+
+    fn new(address: Address, nonce: u64) -> Self {
+        let mut input = Self {
+            words: [[0; N]; 16],
+            nonce,
+            work: 0,
+            midstate: Midstate::default(),
+        };
+
+        for lane in 0..N {
+            for (i, &b) in address.as_bytes().iter().enumerate() {
+                input.set_byte(lane, i, b);
+            }
+
+            // padding
+            let length = ScalarKernelInput::LENGTH;
+            input.set_byte(lane, length, 0x80);
+            input.set_byte(lane, 62, (((length * 8) >> 8) & 0xFF) as u8);
+            input.set_byte(lane, 63, ((length * 8) & 0xFF) as u8);
+        }
+
+        input.write_nonces();
+        input
+    }
+
+    fn set_block(&mut self, block: &[u8; 12]) {
+        for lane in 0..N {
+            for (i, &b) in block.iter().enumerate() {
+                self.set_byte(lane, Address::LENGTH + i, b);
+            }
+        }
+
+        // the constant words are the same in every lane
+        let mut words = [0; 16];
+        for (w, lanes) in words.iter_mut().zip(&self.words) {
+            *w = lanes[0];
+        }
+
+        self.midstate = Midstate::new(&words);
+    }
+
+    fn set_work(&mut self, work: u64) {
+        self.work = work;
+    }
+
+    fn increment_nonce(&mut self) {
+        self.nonce = self.nonce.wrapping_add(N as u64);
+        self.write_nonces();
+    }
+
+    /// A mask of the lanes which found a solution
+    type Score = u32;
+
+    #[inline(always)]
+    fn get_solution(&mut self, _work: u64, mask: u32) -> Option<Nonce> {
+        if mask != 0 {
+            Some(self.nonce(mask.trailing_zeros() as usize))
+        } else {
+            None
+        }
+    }
+}
+
+impl<const N: usize> VectorKernelInput<N> {
+    const NONCE_OFFSET: usize = ScalarKernelInput::LENGTH - Nonce::LENGTH;
+
+    #[inline(always)]
+    fn set_byte(&mut self, lane: usize, index: usize, value: u8) {
+        let shift = 24 - 8 * (index % 4);
+        let word = &mut self.words[index / 4][lane];
+        *word = (*word & !(0xFF << shift)) | (value as u32) << shift;
+    }
+
+    #[inline(always)]
+    fn write_nonces(&mut self) {
+        for lane in 0..N {
+            let n = self.nonce.wrapping_add(lane as u64);
+
+            for i in 0..Nonce::LENGTH {
+                self.set_byte(lane, Self::NONCE_OFFSET + i, Nonce::encode_char(n, i));
+            }
+        }
+    }
+
+    /// Get the nonce for the given lane
+    pub fn nonce(&self, lane: usize) -> Nonce {
+        Nonce::from_counter(self.nonce.wrapping_add(lane as u64))
+    }
+
+    /// Get the data for the given lane
+    #[cfg(test)]
+    pub fn data(&self, lane: usize) -> Vec<u8> {
+        self.words
+            .iter()
+            .flat_map(|w| w[lane].to_be_bytes())
+            .take(ScalarKernelInput::LENGTH)
+            .collect()
+    }
+
+    /// Get the interleaved message words of the padded data block
+    pub fn words(&self) -> &[[u32; N]; 16] {
+        &self.words
+    }
+
+    /// Get the state precomputed by [`set_block`](KernelInput::set_block)
+    pub fn midstate(&self) -> &Midstate {
+        &self.midstate
+    }
+
+    /// Get the mask of lanes whose hashes, given by the first two words of
+    /// their digests, solve the block for the current work
+    #[inline(always)]
+    pub fn solution_mask(&self, a: &[u32; N], b: &[u32; N]) -> u32 {
+        (0..N)
+            .filter(|&i| ((a[i] as u64) << 16 | (b[i] as u64) >> 16) <= self.work)
+            .fold(0, |mask, i| mask | 1 << i)
+    }
 }
 
 /// A CPU mining kernel.
@@ -107,12 +294,74 @@ pub trait Kernel {
     fn score(&self, input: &Self::Input) -> <Self::Input as KernelInput>::Score;
 }
 
+/// The number of kernel calls each CPU miner thread makes per nonce range
+const BATCH_SIZE: u64 = 10_000;
+
+/// Hashes between checks for a new target, a power of two
+const EPOCH_CHECK_INTERVAL: u64 = 256;
+
+/// A target for CPU miner threads, and the epoch it was sent in
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerTarget {
+    /// The block, for allocating nonces
+    pub hash: ShortHash,
+
+    /// The block as hex, for kernel input
+    pub block: [u8; 12],
+    pub work: u64,
+    pub epoch: u64,
+}
+
+impl WorkerTarget {
+    /// Get the target to mine for the given target state, or `None` if
+    /// mining should stop or pause
+    pub fn new(current: CurrentTarget, epoch: u64) -> Option<Self> {
+        match current {
+            CurrentTarget::New(t) | CurrentTarget::Unchanged(t) => Some(Self {
+                hash: t.block,
+                block: t.block.into_hex().as_bytes().try_into().unwrap(),
+                work: t.work,
+                epoch,
+            }),
+            CurrentTarget::Paused | CurrentTarget::StopMining => None,
+        }
+    }
+}
+
+/// State shared by a CPU miner's threads, set by its dispatch thread
+pub struct SharedState {
+    /// The target to mine, or `None` to stop
+    pub target: AtomicCell<Option<WorkerTarget>>,
+    pub paused: AtomicBool,
+    pub nonces: NonceAllocator,
+
+    /// Advanced whenever a new target is sent, before the dispatch thread
+    /// updates `target`
+    pub epoch: Epoch,
+
+    /// The longest time a thread kept working on a target after a newer one
+    /// was sent, in nanoseconds
+    pub stale_nanos: AtomicU64,
+}
+
+impl SharedState {
+    pub fn new(nonces: NonceAllocator, epoch: Epoch) -> Self {
+        Self {
+            target: AtomicCell::new(None),
+            paused: AtomicBool::new(false),
+            nonces,
+            epoch,
+            stale_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
 pub struct Context<'a> {
     address: Address,
     hashes: &'a AtomicU64,
-    target: &'a AtomicCell<Option<([u8; 12], u64)>>,
-    nonce: u64,
-    sol_tx: &'a Sender<String>,
+    shared: &'a SharedState,
+    sol_tx: &'a Sender<Nonce>,
+    max_usage: UsageLimit,
 }
 
 impl<'a> Context<'a> {
@@ -120,28 +369,64 @@ impl<'a> Context<'a> {
     pub fn new(
         address: Address,
         hashes: &'a AtomicU64,
-        target: &'a AtomicCell<Option<([u8; 12], u64)>>,
-        nonce: u64,
-        sol_tx: &'a Sender<String>,
+        shared: &'a SharedState,
+        sol_tx: &'a Sender<Nonce>,
+        max_usage: UsageLimit,
     ) -> Self {
         Self {
             address,
             hashes,
-            target,
-            nonce,
+            shared,
             sol_tx,
+            max_usage,
         }
     }
 
     /// Mine synchronously using this context and the given kernel.
     pub fn mine<K: Kernel>(self, kernel: K) {
-        const BATCH_SIZE: u64 = 10_000;
-        let mut input = K::Input::new(self.address, self.nonce);
+        let mut throttle = self.max_usage.throttle();
+
+        while let Some(WorkerTarget {
+            hash,
+            block,
+            work,
+            epoch,
+        }) = self.shared.target.load()
+        {
+            if self.shared.paused.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
 
-        while let Some((block, work)) = self.target.load() {
+            // a newer target was sent, which the dispatch thread hasn't passed
+            // on yet
+            if self.shared.epoch.current() != epoch {
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+
+            // each batch hashes a fresh range of nonces, shared out between
+            // every thread and device mining on this block
+            let batch_start = Instant::now();
+            let range = self
+                .shared
+                .nonces
+                .allocate(hash, BATCH_SIZE * K::Input::LANES);
+
+            let mut input = K::Input::new(self.address, range.start);
             input.set_block(&block);
+            input.set_work(work);
+
+            let mut hashed = 0;
+            while hashed < BATCH_SIZE {
+                if hashed % EPOCH_CHECK_INTERVAL == 0 && self.shared.epoch.current() != epoch {
+                    let stale = self.shared.epoch.started().elapsed();
+                    self.shared
+                        .stale_nanos
+                        .fetch_max(stale.as_nanos() as u64, Ordering::Relaxed);
+                    break;
+                }
 
-            for _ in 0..BATCH_SIZE {
                 let score = kernel.score(&input);
                 if let Some(solution) = input.get_solution(work, score) {
                     // solution found!
@@ -150,9 +435,162 @@ impl<'a> Context<'a> {
                     }
                 }
                 input.increment_nonce();
+                hashed += 1;
             }
 
-            self.hashes.fetch_add(BATCH_SIZE, Ordering::Relaxed);
+            self.hashes
+                .fetch_add(hashed * K::Input::LANES, Ordering::Relaxed);
+
+            if let Some(throttle) = &mut throttle {
+                throttle.throttle(batch_start.elapsed());
+            }
         }
     }
 }
+
+/// Time hashing (at least) `hashes` nonces with the given kernel on the
+/// current thread, using an arbitrary address and block which are never
+/// solved
+pub fn time_kernel<K: Kernel>(kernel: K, hashes: u64) -> Duration {
+    let mut input = K::Input::new(Address::from_str("k5ztameslf").unwrap(), 0);
+    input.set_block(b"000000000000");
+    input.set_work(0);
+
+    let start = Instant::now();
+
+    for _ in 0..hashes.div_ceil(K::Input::LANES) {
+        let score = kernel.score(&input);
+        std::hint::black_box(input.get_solution(0, score));
+        input.increment_nonce();
+    }
+
+    start.elapsed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner::nonces::NonceRange;
+    use std::sync::Mutex;
+
+    /// A kernel recording the nonces it hashes, which stops mining after one
+    /// batch
+    struct Recorder<'a> {
+        shared: &'a SharedState,
+        nonces: &'a Mutex<Vec<Nonce>>,
+    }
+
+    impl Kernel for Recorder<'_> {
+        type Input = ScalarKernelInput;
+
+        fn score(&self, input: &ScalarKernelInput) -> u64 {
+            let mut nonces = self.nonces.lock().unwrap();
+            nonces.push(input.nonce());
+            if nonces.len() as u64 == BATCH_SIZE {
+                self.shared.target.store(None);
+            }
+            u64::MAX
+        }
+    }
+
+    #[test]
+    fn test_scalar_batch_hashes_allocated_range() {
+        let block = ShortHash::from_str("abce8f03b1d2").unwrap();
+        let target = crate::miner::Target { work: 0, block };
+        let (_target_tx, target_rx) = crate::miner::watch::channel::<()>();
+        let shared = SharedState::new(NonceAllocator::new(), target_rx.epoch());
+        shared
+            .target
+            .store(WorkerTarget::new(CurrentTarget::New(target), 0));
+
+        let hashes = AtomicU64::new(0);
+        let (sol_tx, _sol_rx) = crossbeam::channel::unbounded();
+        let nonces = Mutex::new(vec![]);
+        let recorder = Recorder {
+            shared: &shared,
+            nonces: &nonces,
+        };
+
+        Context::new(
+            Address::from_str("k5ztameslf").unwrap(),
+            &hashes,
+            &shared,
+            &sol_tx,
+            UsageLimit::UNLIMITED,
+        )
+        .mine(recorder);
+
+        // the batch's range ends where the next allocation starts
+        let next = shared.nonces.allocate(block, 1).start;
+        let range = NonceRange {
+            start: next.wrapping_sub(BATCH_SIZE),
+            len: BATCH_SIZE,
+        };
+
+        let nonces = nonces.into_inner().unwrap();
+        assert_eq!(nonces.len() as u64, BATCH_SIZE);
+        assert_eq!(nonces[0], Nonce::from_counter(range.start));
+        assert_eq!(
+            nonces[nonces.len() - 1],
+            Nonce::from_counter(range.start.wrapping_add(range.len - 1))
+        );
+    }
+
+    /// A kernel which sends a new target after hashing `after` nonces, and
+    /// counts every nonce it hashes
+    struct Preempter<'a> {
+        shared: &'a SharedState,
+        target_tx: &'a crate::miner::watch::Sender<()>,
+        after: u64,
+        hashed: &'a AtomicU64,
+    }
+
+    impl Kernel for Preempter<'_> {
+        type Input = ScalarKernelInput;
+
+        fn score(&self, _input: &ScalarKernelInput) -> u64 {
+            if self.hashed.fetch_add(1, Ordering::Relaxed) + 1 == self.after {
+                self.target_tx.send(()).unwrap();
+                self.shared.target.store(None);
+            }
+            u64::MAX
+        }
+    }
+
+    #[test]
+    fn test_new_epoch_stops_batch() {
+        let block = ShortHash::from_str("abce8f03b1d2").unwrap();
+        let target = crate::miner::Target { work: 0, block };
+        let (target_tx, target_rx) = crate::miner::watch::channel::<()>();
+        let shared = SharedState::new(NonceAllocator::new(), target_rx.epoch());
+        shared
+            .target
+            .store(WorkerTarget::new(CurrentTarget::New(target), 0));
+
+        let hashes = AtomicU64::new(0);
+        let hashed = AtomicU64::new(0);
+        let (sol_tx, _sol_rx) = crossbeam::channel::unbounded();
+        let after = EPOCH_CHECK_INTERVAL + 10;
+
+        Context::new(
+            Address::from_str("k5ztameslf").unwrap(),
+            &hashes,
+            &shared,
+            &sol_tx,
+            UsageLimit::UNLIMITED,
+        )
+        .mine(Preempter {
+            shared: &shared,
+            target_tx: &target_tx,
+            after,
+            hashed: &hashed,
+        });
+
+        // the worker notices the new epoch at its next check, well before the
+        // end of the batch, and still counts the hashes it did
+        let hashed = hashed.load(Ordering::Relaxed);
+        assert_eq!(hashed, 2 * EPOCH_CHECK_INTERVAL);
+        assert!(hashed < BATCH_SIZE);
+        assert_eq!(hashes.load(Ordering::Relaxed), hashed);
+    }
+}
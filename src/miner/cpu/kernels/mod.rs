@@ -1,8 +1,18 @@
+mod portable;
+#[cfg(target_arch = "x86_64")]
 mod sha;
+#[cfg(target_arch = "x86_64")]
+mod simd;
 mod unoptimized;
 
+#[cfg(target_arch = "x86_64")]
+use super::framework::VectorKernelInput;
 use super::framework::{Kernel, ScalarKernelInput};
+pub use portable::Portable;
+#[cfg(target_arch = "x86_64")]
 pub use sha::SHA;
+#[cfg(target_arch = "x86_64")]
+pub use simd::{AVX2, SSE2};
 pub use unoptimized::Unoptimized;
 
 /// Calculate the score from the raw a/b variables of the hash state
@@ -11,39 +21,108 @@ fn score_ab(a: u32, b: u32) -> u64 {
     (a.to_le() as u64) << 16 | (b as u64) >> 16
 }
 
-/// Calculate the score from the hash output (len must be >= 6)
-#[inline(always)]
-fn score_output(h: &[u8]) -> u64 {
-    h[..6]
-        .iter()
-        .enumerate()
-        .map(|(i, &v)| (v as u64) << (40 - (8 * i)))
-        .sum()
-}
-
 #[cfg(test)]
 mod tests {
-    use super::super::framework::KernelInput;
+    use super::super::framework::{time_kernel, KernelInput, VectorKernelInput};
+    use super::super::get_supported_kernels;
     use super::*;
     use crate::krist::address::Address;
+    use crate::krist::nonce::Nonce;
     use ring::digest::{digest, SHA256};
     use std::str::FromStr;
+    use std::time::Duration;
+
+    /// A kernel hashing the whole input every time, without any precomputed
+    /// state, as a baseline for the optimized kernels
+    struct FullHash;
+    impl Kernel for FullHash {
+        type Input = ScalarKernelInput;
+
+        fn score(&self, input: &ScalarKernelInput) -> u64 {
+            let hash = digest(&SHA256, input.data());
+            hash.as_ref()[..6]
+                .iter()
+                .fold(0, |score, &b| score << 8 | b as u64)
+        }
+    }
 
     fn test_scalar_kernel(kernel: impl Kernel<Input = ScalarKernelInput>) {
-        let mut input = ScalarKernelInput::new(Address::from_str("k5ztameslf").unwrap(), 0);
-        input.set_block(&*b"abce8f03b1d2");
+        // start just before the counter wraps, so every nonce character
+        // changes while reusing the precomputed state
+        let mut input =
+            ScalarKernelInput::new(Address::from_str("k5ztameslf").unwrap(), u64::MAX - 2);
+        input.set_block(b"abce8f03b1d2");
+
+        for _ in 0..4 {
+            let expected_hex = hex::encode(digest(&SHA256, input.data()).as_ref());
+            let expected = u64::from_str_radix(&expected_hex[..12], 16).unwrap();
 
-        let expected_hex = hex::encode(digest(&SHA256, input.data()).as_ref());
-        let expected = u64::from_str_radix(&expected_hex[..12], 16).unwrap();
+            let actual = kernel.score(&input);
 
-        let actual = kernel.score(&input);
+            assert_eq!(
+                expected,
+                actual,
+                "hash score mismatch for input '{}'",
+                String::from_utf8_lossy(input.data())
+            );
 
-        assert_eq!(
-            expected,
-            actual,
-            "hash score mismatch for input '{}'",
-            String::from_utf8_lossy(input.data())
-        )
+            input.increment_nonce();
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn test_vector_kernel<const N: usize>(kernel: impl Kernel<Input = VectorKernelInput<N>>) {
+        // lanes span the counter wrapping, so every nonce character differs
+        let mut input =
+            VectorKernelInput::<N>::new(Address::from_str("k5ztameslf").unwrap(), u64::MAX - 2);
+        input.set_block(b"abce8f03b1d2");
+
+        let scores: Vec<u64> = (0..N)
+            .map(|lane| {
+                let expected_hex = hex::encode(digest(&SHA256, &input.data(lane)).as_ref());
+                u64::from_str_radix(&expected_hex[..12], 16).unwrap()
+            })
+            .collect();
+
+        // with the work set to each lane's score in turn, exactly the lanes
+        // with an equal or lower score should be solutions
+        for (lane, &work) in scores.iter().enumerate() {
+            input.set_work(work);
+
+            let expected = (0..N)
+                .filter(|&i| scores[i] <= work)
+                .fold(0, |mask, i| mask | 1 << i);
+
+            assert_eq!(
+                expected,
+                kernel.score(&input),
+                "solution mask mismatch for lane {} input '{}'",
+                lane,
+                String::from_utf8_lossy(&input.data(lane))
+            );
+        }
+    }
+
+    #[test]
+    fn test_vector_input_nonce() {
+        let mut input = VectorKernelInput::<4>::new(Address::from_str("k5ztameslf").unwrap(), 40);
+        assert_eq!(input.nonce(3), Nonce::from_counter(43));
+        assert_eq!(&input.data(3)[22..], Nonce::from_counter(43).as_bytes());
+
+        input.increment_nonce();
+        assert_eq!(&input.data(0)[22..], Nonce::from_counter(44).as_bytes());
+        assert_eq!(input.get_solution(0, 0b0100), Some(Nonce::from_counter(46)));
+        assert_eq!(input.get_solution(0, 0), None);
+    }
+
+    #[test]
+    fn test_scalar_input_nonce() {
+        let mut input = ScalarKernelInput::new(Address::from_str("k5ztameslf").unwrap(), 41);
+        assert_eq!(input.nonce(), Nonce::from_counter(41));
+        assert_eq!(&input.data()[22..], Nonce::from_counter(41).as_bytes());
+
+        input.increment_nonce();
+        assert_eq!(&input.data()[22..], Nonce::from_counter(42).as_bytes());
     }
 
     #[test]
@@ -52,9 +131,57 @@ mod tests {
     }
 
     #[test]
+    fn test_portable_kernel() {
+        test_scalar_kernel(Portable);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_sse2_kernel() {
+        if is_x86_feature_detected!("sse2") {
+            test_vector_kernel(SSE2);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_avx2_kernel() {
+        if is_x86_feature_detected!("avx2") {
+            test_vector_kernel(AVX2);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
     fn test_sha_kernel() {
         if is_x86_feature_detected!("sha") {
             test_scalar_kernel(SHA);
         }
     }
+
+    /// Compare the single-thread hashrate of each supported kernel with
+    /// hashing the whole input. Run with
+    /// `cargo test --release bench_kernels -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_kernels() {
+        const HASHES: u64 = 1 << 22;
+        let rate = |time: Duration| HASHES as f64 / time.as_secs_f64() / 1_000_000.;
+
+        time_kernel(FullHash, HASHES / 8);
+        let baseline = rate(time_kernel(FullHash, HASHES));
+        println!("full hash: {:.2} Mh/s", baseline);
+
+        for kernel in get_supported_kernels() {
+            // warm up caches and clock speeds before timing
+            kernel.time(HASHES / 8);
+            let hashrate = rate(kernel.time(HASHES));
+            println!(
+                "{}: {:.2} Mh/s ({:.2}x)",
+                kernel,
+                hashrate,
+                hashrate / baseline
+            );
+        }
+    }
 }
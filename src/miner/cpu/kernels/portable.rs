@@ -0,0 +1,62 @@
+use super::super::sha256::{expand, round, HighNonceTerms, Midstate, IV};
+use super::{score_ab, Kernel, ScalarKernelInput};
+use std::convert::TryInto;
+
+/// Expand `$body` once for each index, binding it to the constant `$i`
+macro_rules! unroll {
+    ($i:ident in [$($n:expr),* $(,)?] $body:block) => {
+        $({
+            const $i: usize = $n;
+            $body
+        })*
+    };
+}
+
+/// Portable kernel in plain Rust, for any CPU architecture
+pub struct Portable;
+impl Kernel for Portable {
+    type Input = ScalarKernelInput;
+
+    #[inline(always)]
+    fn score(&self, input: &ScalarKernelInput) -> u64 {
+        let (a, b) = digest_ab(input.midstate(), input.high_terms(), input.data_block());
+        score_ab(a, b)
+    }
+}
+
+/// Compute the first two words of the SHA-256 digest of a single padded
+/// block, starting from the precomputed midstate and schedule terms
+#[inline(always)]
+fn digest_ab(midstate: &Midstate, high_terms: &HighNonceTerms, data: &[u8; 64]) -> (u32, u32) {
+    let mut w = [0u32; 64];
+
+    // word 4 is only needed to expand word 20
+    unroll!(I in [4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15] {
+        w[I] = u32::from_be_bytes(data[I * 4..I * 4 + 4].try_into().unwrap());
+    });
+
+    w[16..20].copy_from_slice(&midstate.schedule);
+
+    w[20] = expand(&w, 20);
+    w[21] = expand(&w, 21);
+    high_terms.expand(&mut w);
+
+    unroll!(I in [
+        25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46,
+        47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+    ] {
+        w[I] = expand(&w, I);
+    });
+
+    let mut state = midstate.state5;
+
+    unroll!(I in [
+        5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28,
+        29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51,
+        52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+    ] {
+        round(&mut state, I, w[I]);
+    });
+
+    (state[0].wrapping_add(IV[0]), state[1].wrapping_add(IV[1]))
+}
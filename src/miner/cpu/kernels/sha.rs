@@ -1,9 +1,11 @@
 #![allow(
     clippy::unreadable_literal,
+    clippy::upper_case_acronyms,
     clippy::cast_ptr_alignment,
     overflowing_literals
 )]
 
+use super::super::sha256::{Midstate, IV};
 use super::{score_ab, Kernel, ScalarKernelInput};
 use std::arch::x86_64::*;
 
@@ -13,30 +15,41 @@ impl Kernel for SHA {
 
     #[inline(always)]
     fn score(&self, input: &ScalarKernelInput) -> u64 {
-        let [a, b, _, _, _, _, _, _] = digest(input.data_block());
+        let [a, b, _, _, _, _, _, _] = digest(input.midstate(), input.data_block());
         score_ab(a, b)
     }
 }
 
-/// SHA256 digest of a single padded block of data.
+/// SHA256 digest of a single padded block of data, starting from the state
+/// after the first 4 rounds.
 #[inline(always)]
-fn digest(data: &[u8; 64]) -> [u32; 8] {
-    // initial state
-    let mut state = [
-        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
-        0x5be0cd19,
-    ];
+fn digest(midstate: &Midstate, data: &[u8; 64]) -> [u32; 8] {
+    let mut state = [0; 8];
 
-    unsafe { process(&mut state, &data) };
+    unsafe { process(&mut state, midstate, data) };
 
     state
 }
 
-/// Process multiple blocks. The caller is responsible for setting the initial
-/// state, and the caller is responsible for padding the final block.
+/// Load a state into the ABEF/CDGH layout used by the SHA instructions
+#[inline(always)]
+unsafe fn load_state(state: &[u32; 8]) -> (__m128i, __m128i) {
+    let tmp = _mm_loadu_si128(state.as_ptr() as _);
+    let state1 = _mm_loadu_si128(state.as_ptr().add(4) as _);
+
+    let tmp = _mm_shuffle_epi32(tmp, 0xb1); /* CDAB */
+    let state1 = _mm_shuffle_epi32(state1, 0x1b); /* EFGH */
+    let state0 = _mm_alignr_epi8(tmp, state1, 8); /* ABEF */
+    let state1 = _mm_blend_epi16(state1, tmp, 0xf0); /* CDGH */
+
+    (state0, state1)
+}
+
+/// Process a single padded block, skipping the rounds and message schedule
+/// words precomputed in the midstate.
 #[target_feature(enable = "sha")]
 #[target_feature(enable = "sse4.1")]
-unsafe fn process(state: &mut [u32; 8], data: &[u8; 64]) {
+unsafe fn process(state: &mut [u32; 8], midstate: &Midstate, data: &[u8; 64]) {
     let mask = _mm_set_epi64x(0x0c0d0e0f08090a0b, 0x0405060700010203);
 
     let mut state0: __m128i;
@@ -47,29 +60,12 @@ unsafe fn process(state: &mut [u32; 8], data: &[u8; 64]) {
     let mut msg1: __m128i;
     let mut msg2: __m128i;
     let mut msg3: __m128i;
-    let abef_save: __m128i;
-    let cdgh_save: __m128i;
-
-    /* Load initial values */
-    tmp = _mm_loadu_si128(state.as_ptr() as _);
-    state1 = _mm_loadu_si128(state.as_ptr().add(4) as _);
-
-    tmp = _mm_shuffle_epi32(tmp, 0xb1); /* CDAB */
-    state1 = _mm_shuffle_epi32(state1, 0x1b); /* EFGH */
-    state0 = _mm_alignr_epi8(tmp, state1, 8); /* ABEF */
-    state1 = _mm_blend_epi16(state1, tmp, 0xf0); /* CDGH */
-
-    /* Save current state */
-    abef_save = state0;
-    cdgh_save = state1;
-
-    /* Rounds 0-3 */
-    msg = _mm_loadu_si128(data.as_ptr().add(0) as _);
-    msg0 = _mm_shuffle_epi8(msg, mask);
-    msg = _mm_add_epi32(msg0, _mm_set_epi64x(0xE9B5DBA5B5C0FBCF, 0x71374491428A2F98));
-    state1 = _mm_sha256rnds2_epu32(state1, state0, msg);
-    msg = _mm_shuffle_epi32(msg, 0x0E);
-    state0 = _mm_sha256rnds2_epu32(state0, state1, msg);
+
+    /* Load state after rounds 0-3 */
+    (state0, state1) = load_state(&midstate.state4);
+
+    /* The final state is added to the initial state, not the midstate */
+    let (abef_save, cdgh_save) = load_state(&IV);
 
     /* Rounds 4-7 */
     msg1 = _mm_loadu_si128(data.as_ptr().add(16) as _);
@@ -78,7 +74,6 @@ unsafe fn process(state: &mut [u32; 8], data: &[u8; 64]) {
     state1 = _mm_sha256rnds2_epu32(state1, state0, msg);
     msg = _mm_shuffle_epi32(msg, 0x0E);
     state0 = _mm_sha256rnds2_epu32(state0, state1, msg);
-    msg0 = _mm_sha256msg1_epu32(msg0, msg1);
 
     /* Rounds 8-11 */
     msg2 = _mm_loadu_si128(data.as_ptr().add(32) as _);
@@ -94,9 +89,7 @@ unsafe fn process(state: &mut [u32; 8], data: &[u8; 64]) {
     msg3 = _mm_shuffle_epi8(msg3, mask);
     msg = _mm_add_epi32(msg3, _mm_set_epi64x(0xC19BF1749BDC06A7, 0x80DEB1FE72BE5D74));
     state1 = _mm_sha256rnds2_epu32(state1, state0, msg);
-    tmp = _mm_alignr_epi8(msg3, msg2, 4);
-    msg0 = _mm_add_epi32(msg0, tmp);
-    msg0 = _mm_sha256msg2_epu32(msg0, msg3);
+    msg0 = _mm_loadu_si128(midstate.schedule.as_ptr() as _);
     msg = _mm_shuffle_epi32(msg, 0x0E);
     state0 = _mm_sha256rnds2_epu32(state0, state1, msg);
     msg2 = _mm_sha256msg1_epu32(msg2, msg3);
@@ -0,0 +1,224 @@
+//! Multi-lane SHA-256 kernels, hashing one nonce per SIMD lane
+
+#![allow(clippy::upper_case_acronyms)]
+
+use super::super::sha256::{Midstate, IV, K};
+use super::{Kernel, VectorKernelInput};
+use std::arch::x86_64::*;
+
+/// A vector of 32-bit lanes. All methods must only be called from functions
+/// with the corresponding target features enabled.
+trait Lanes: Copy {
+    unsafe fn splat(v: u32) -> Self;
+    unsafe fn load(p: *const u32) -> Self;
+    unsafe fn store(self, p: *mut u32);
+    unsafe fn add(self, other: Self) -> Self;
+    unsafe fn and(self, other: Self) -> Self;
+    unsafe fn or(self, other: Self) -> Self;
+    unsafe fn xor(self, other: Self) -> Self;
+    unsafe fn shr<const S: i32>(self) -> Self;
+    unsafe fn shl<const S: i32>(self) -> Self;
+
+    /// Rotate right by `R` bits, where `L` must be `32 - R`
+    #[inline(always)]
+    unsafe fn rotr<const R: i32, const L: i32>(self) -> Self {
+        self.shr::<R>().or(self.shl::<L>())
+    }
+}
+
+impl Lanes for __m128i {
+    #[inline(always)]
+    unsafe fn splat(v: u32) -> Self {
+        _mm_set1_epi32(v as i32)
+    }
+
+    #[inline(always)]
+    unsafe fn load(p: *const u32) -> Self {
+        _mm_loadu_si128(p as _)
+    }
+
+    #[inline(always)]
+    unsafe fn store(self, p: *mut u32) {
+        _mm_storeu_si128(p as _, self)
+    }
+
+    #[inline(always)]
+    unsafe fn add(self, other: Self) -> Self {
+        _mm_add_epi32(self, other)
+    }
+
+    #[inline(always)]
+    unsafe fn and(self, other: Self) -> Self {
+        _mm_and_si128(self, other)
+    }
+
+    #[inline(always)]
+    unsafe fn or(self, other: Self) -> Self {
+        _mm_or_si128(self, other)
+    }
+
+    #[inline(always)]
+    unsafe fn xor(self, other: Self) -> Self {
+        _mm_xor_si128(self, other)
+    }
+
+    #[inline(always)]
+    unsafe fn shr<const S: i32>(self) -> Self {
+        _mm_srli_epi32::<S>(self)
+    }
+
+    #[inline(always)]
+    unsafe fn shl<const S: i32>(self) -> Self {
+        _mm_slli_epi32::<S>(self)
+    }
+}
+
+impl Lanes for __m256i {
+    #[inline(always)]
+    unsafe fn splat(v: u32) -> Self {
+        _mm256_set1_epi32(v as i32)
+    }
+
+    #[inline(always)]
+    unsafe fn load(p: *const u32) -> Self {
+        _mm256_loadu_si256(p as _)
+    }
+
+    #[inline(always)]
+    unsafe fn store(self, p: *mut u32) {
+        _mm256_storeu_si256(p as _, self)
+    }
+
+    #[inline(always)]
+    unsafe fn add(self, other: Self) -> Self {
+        _mm256_add_epi32(self, other)
+    }
+
+    #[inline(always)]
+    unsafe fn and(self, other: Self) -> Self {
+        _mm256_and_si256(self, other)
+    }
+
+    #[inline(always)]
+    unsafe fn or(self, other: Self) -> Self {
+        _mm256_or_si256(self, other)
+    }
+
+    #[inline(always)]
+    unsafe fn xor(self, other: Self) -> Self {
+        _mm256_xor_si256(self, other)
+    }
+
+    #[inline(always)]
+    unsafe fn shr<const S: i32>(self) -> Self {
+        _mm256_srli_epi32::<S>(self)
+    }
+
+    #[inline(always)]
+    unsafe fn shl<const S: i32>(self) -> Self {
+        _mm256_slli_epi32::<S>(self)
+    }
+}
+
+/// Compute the first two words of the SHA-256 digest of a single padded
+/// block in each lane, starting from the precomputed midstate
+#[inline(always)]
+unsafe fn digest_ab<V: Lanes>(midstate: &Midstate, mut w: [V; 16]) -> (V, V) {
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] =
+        midstate.state5.map(|v| V::splat(v));
+
+    for i in Midstate::CONSTANT_WORDS..64 {
+        if (16..20).contains(&i) {
+            w[i % 16] = V::splat(midstate.schedule[i - 16]);
+        } else if i >= 20 {
+            let w15 = w[(i + 1) % 16];
+            let w2 = w[(i + 14) % 16];
+            let s0 = w15
+                .rotr::<7, 25>()
+                .xor(w15.rotr::<18, 14>())
+                .xor(w15.shr::<3>());
+            let s1 = w2
+                .rotr::<17, 15>()
+                .xor(w2.rotr::<19, 13>())
+                .xor(w2.shr::<10>());
+            w[i % 16] = w[i % 16].add(s0).add(w[(i + 9) % 16]).add(s1);
+        }
+
+        let s1 = e
+            .rotr::<6, 26>()
+            .xor(e.rotr::<11, 21>())
+            .xor(e.rotr::<25, 7>());
+        let ch = g.xor(e.and(f.xor(g)));
+        let t1 = h.add(s1).add(ch).add(V::splat(K[i])).add(w[i % 16]);
+
+        let s0 = a
+            .rotr::<2, 30>()
+            .xor(a.rotr::<13, 19>())
+            .xor(a.rotr::<22, 10>());
+        let maj = a.and(b).or(c.and(a.or(b)));
+        let t2 = s0.add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.add(t2);
+    }
+
+    (a.add(V::splat(IV[0])), b.add(V::splat(IV[1])))
+}
+
+/// Hash every lane of the input, returning the mask of solutions. `V` must
+/// hold exactly `N` lanes.
+#[inline(always)]
+unsafe fn solution_mask<V: Lanes, const N: usize>(input: &VectorKernelInput<N>) -> u32 {
+    let words = input.words();
+    let mut w = [V::splat(0); 16];
+    for (v, lanes) in w.iter_mut().zip(words) {
+        *v = V::load(lanes.as_ptr());
+    }
+
+    let (a, b) = digest_ab(input.midstate(), w);
+
+    let mut a_out = [0u32; N];
+    let mut b_out = [0u32; N];
+    a.store(a_out.as_mut_ptr());
+    b.store(b_out.as_mut_ptr());
+
+    input.solution_mask(&a_out, &b_out)
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn solution_mask_sse2(input: &VectorKernelInput<4>) -> u32 {
+    solution_mask::<__m128i, 4>(input)
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn solution_mask_avx2(input: &VectorKernelInput<8>) -> u32 {
+    solution_mask::<__m256i, 8>(input)
+}
+
+/// 4-way kernel using SSE2 instructions
+pub struct SSE2;
+impl Kernel for SSE2 {
+    type Input = VectorKernelInput<4>;
+
+    #[inline(always)]
+    fn score(&self, input: &VectorKernelInput<4>) -> u32 {
+        unsafe { solution_mask_sse2(input) }
+    }
+}
+
+/// 8-way kernel using AVX2 instructions
+pub struct AVX2;
+impl Kernel for AVX2 {
+    type Input = VectorKernelInput<8>;
+
+    #[inline(always)]
+    fn score(&self, input: &VectorKernelInput<8>) -> u32 {
+        unsafe { solution_mask_avx2(input) }
+    }
+}
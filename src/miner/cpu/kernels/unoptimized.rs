@@ -1,12 +1,32 @@
-use super::{score_output, Kernel, ScalarKernelInput};
-use ring::digest::{digest, SHA256};
+use super::super::sha256::{expand, round, IV};
+use super::{score_ab, Kernel, ScalarKernelInput};
+use std::convert::TryInto;
 
+/// Reference kernel in plain Rust loops, resuming from the precomputed
+/// midstate
 pub struct Unoptimized;
 impl Kernel for Unoptimized {
     type Input = ScalarKernelInput;
 
     #[inline(always)]
     fn score(&self, input: &ScalarKernelInput) -> u64 {
-        score_output(digest(&SHA256, input.data()).as_ref())
+        let midstate = input.midstate();
+        let mut w = [0u32; 64];
+
+        for (w, bytes) in w.iter_mut().zip(input.data_block().chunks_exact(4)) {
+            *w = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+
+        w[16..20].copy_from_slice(&midstate.schedule);
+        for i in 20..64 {
+            w[i] = expand(&w, i);
+        }
+
+        let mut state = midstate.state5;
+        for (i, &w) in w.iter().enumerate().skip(5) {
+            round(&mut state, i, w);
+        }
+
+        score_ab(state[0].wrapping_add(IV[0]), state[1].wrapping_add(IV[1]))
     }
 }
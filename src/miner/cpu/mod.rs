@@ -1,30 +1,53 @@
+//! Native CPU miner
+
+pub mod calibrate;
 mod framework;
 mod kernels;
-mod thread_priority;
+mod sha256;
+pub mod topology;
 
-use crate::miner::cpu::framework::Context;
+use crate::miner::control::{ControlError, PendingChange, Reconfigure};
+use crate::miner::cpu::framework::{time_kernel, Context, SharedState, WorkerTarget};
+use crate::miner::cpu::topology::{AffinityPolicy, Topology};
+use crate::miner::events::EventKind;
 use crate::miner::interface::{CurrentTarget, MinerInterface};
+use crate::miner::priority::Priority;
+use crate::miner::throttle::UsageLimit;
 use crate::miner::{Miner, MinerConfig, MinerError};
-use crossbeam::atomic::AtomicCell;
 use crossbeam::channel::RecvTimeoutError;
 use enumset::{EnumSet, EnumSetType};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
-use std::num::Wrapping;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
-/// Select a CPU mining kernel to use
-#[derive(Debug, EnumSetType, PartialOrd, Ord)]
+/// A CPU mining kernel
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Default, EnumSetType, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum KernelType {
-    /// CPU mining kernel with no hardware-specific optimizations.
+    /// CPU mining kernel using plain loops from the precomputed midstate, kept
+    /// as a reference.
     Unoptimized,
 
-    /// CPU mining kernel using x86/x86_64 SHA instructions
+    /// Portable CPU mining kernel with no hardware-specific optimizations.
+    #[default]
+    Portable,
+
+    /// CPU mining kernel hashing 4 nonces at once using x86_64 SSE2
+    /// instructions
+    SSE2,
+
+    /// CPU mining kernel hashing 8 nonces at once using x86_64 AVX2
+    /// instructions
+    AVX2,
+
+    /// CPU mining kernel using x86_64 SHA instructions
     SHA,
 }
 
+/// An error caused by parsing an unknown kernel type
 #[derive(Debug, thiserror::Error)]
 #[error("Invalid kernel type: {0}")]
 pub struct InvalidKernelType(String);
@@ -35,24 +58,59 @@ impl FromStr for KernelType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(match s.to_lowercase().as_ref() {
             "unoptimized" => Self::Unoptimized,
+            "portable" => Self::Portable,
+            "sse2" => Self::SSE2,
+            "avx2" => Self::AVX2,
             "sha" => Self::SHA,
             s => return Err(InvalidKernelType(s.to_string())),
         })
     }
 }
 
-impl KernelType {
-    pub fn mine_with(self, context: Context) {
-        match self {
-            Self::Unoptimized => context.mine(kernels::Unoptimized),
-            Self::SHA => context.mine(kernels::SHA),
+/// Evaluate `$body` with `$kernel` bound to the kernel for a [`KernelType`]
+macro_rules! with_kernel {
+    ($kernel_type:expr, $kernel:ident => $body:expr) => {
+        match $kernel_type {
+            KernelType::Unoptimized => {
+                let $kernel = kernels::Unoptimized;
+                $body
+            }
+            KernelType::Portable => {
+                let $kernel = kernels::Portable;
+                $body
+            }
+            #[cfg(target_arch = "x86_64")]
+            KernelType::SSE2 => {
+                let $kernel = kernels::SSE2;
+                $body
+            }
+            #[cfg(target_arch = "x86_64")]
+            KernelType::AVX2 => {
+                let $kernel = kernels::AVX2;
+                $body
+            }
+            #[cfg(target_arch = "x86_64")]
+            KernelType::SHA => {
+                let $kernel = kernels::SHA;
+                $body
+            }
+            // rejected by `CpuMiner::new` and `CpuMiner::check`
+            #[cfg(not(target_arch = "x86_64"))]
+            k @ (KernelType::SSE2 | KernelType::AVX2 | KernelType::SHA) => {
+                unreachable!("{} kernel is not supported on this architecture", k)
+            }
         }
-    }
+    };
 }
 
-impl Default for KernelType {
-    fn default() -> Self {
-        KernelType::Unoptimized
+impl KernelType {
+    pub(crate) fn mine_with(self, context: Context) {
+        with_kernel!(self, kernel => context.mine(kernel))
+    }
+
+    /// Time hashing `hashes` nonces with this kernel on the current thread
+    pub fn time(self, hashes: u64) -> Duration {
+        with_kernel!(self, kernel => time_kernel(kernel, hashes))
     }
 }
 
@@ -60,6 +118,9 @@ impl Display for KernelType {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let name = match self {
             Self::Unoptimized => "unoptimized",
+            Self::Portable => "portable",
+            Self::SSE2 => "SSE2",
+            Self::AVX2 => "AVX2",
             Self::SHA => "SHA",
         };
 
@@ -67,6 +128,7 @@ impl Display for KernelType {
     }
 }
 
+/// Information about the CPU's mining capabilities
 #[derive(Debug)]
 pub struct CpuInfo {
     threads: usize,
@@ -89,19 +151,35 @@ impl Display for CpuInfo {
 }
 
 fn get_supported_kernels() -> EnumSet<KernelType> {
-    let mut supported = EnumSet::only(KernelType::Unoptimized);
+    #[allow(unused_mut)]
+    let mut supported = KernelType::Unoptimized | KernelType::Portable;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse2") {
+            supported |= KernelType::SSE2;
+        }
 
-    if is_x86_feature_detected!("sha") {
-        supported |= KernelType::SHA;
+        if is_x86_feature_detected!("avx2") {
+            supported |= KernelType::AVX2;
+        }
+
+        if is_x86_feature_detected!("sha") {
+            supported |= KernelType::SHA;
+        }
     }
 
     supported
 }
 
-fn get_best_kernel() -> KernelType {
-    Iterator::max(get_supported_kernels().iter()).unwrap_or_default()
+impl CpuInfo {
+    /// The kernels supported by this CPU
+    pub fn supported_kernels(&self) -> EnumSet<KernelType> {
+        self.supported
+    }
 }
 
+/// Get information about the CPU's mining capabilities
 pub fn get_cpu_info() -> CpuInfo {
     let threads = num_cpus::get();
     let supported = get_supported_kernels();
@@ -109,96 +187,353 @@ pub fn get_cpu_info() -> CpuInfo {
     CpuInfo { threads, supported }
 }
 
+/// How often the CPU miner dispatch thread checks for new targets
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often the CPU miner reports its hashrate
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A miner using one or more CPU threads
+///
+/// CPU miners accept [`Reconfigure::Threads`] and [`Reconfigure::CpuKernel`]
+/// while running, by stopping their threads and starting new ones.
 pub struct CpuMiner {
     kernel_type: KernelType,
+    /// Whether to replace `kernel_type` with the fastest kernel when mining
+    /// starts
+    calibrate: bool,
     threads: usize,
+    /// The CPUs threads may run on, or empty to not pin them. Thread `i` uses
+    /// the entry `i % cpu_sets.len()`.
+    cpu_sets: Vec<Vec<usize>>,
+    /// Describes how threads are pinned, if they are
+    pinning: String,
+    priority: Priority,
+    max_usage: UsageLimit,
 }
 
 impl CpuMiner {
+    /// Create a CPU miner using the thread count, kernel and affinity from the
+    /// given configuration, or the best defaults for this CPU. Fails if the
+    /// configured kernel isn't supported by this CPU.
     pub fn new(
         &MinerConfig {
             cpu_threads,
             cpu_kernel,
+            ref cpu_cores,
+            cpu_affinity,
+            cpu_priority: priority,
+            cpu_max_usage: max_usage,
             ..
         }: &MinerConfig,
-    ) -> CpuMiner {
-        CpuMiner {
-            threads: cpu_threads.unwrap_or_else(num_cpus::get),
-            kernel_type: cpu_kernel.unwrap_or_else(get_best_kernel),
+    ) -> Result<CpuMiner, MinerError> {
+        if let Some(kernel) = cpu_kernel.filter(|&k| !get_supported_kernels().contains(k)) {
+            return Err(MinerError::UnsupportedKernel(kernel));
         }
+
+        let pinned = match cpu_affinity {
+            AffinityPolicy::None => None,
+            policy => match Topology::current() {
+                Ok(topology) => topology
+                    .assign(policy, cpu_cores.as_ref())
+                    .filter(|cpus| !cpus.is_empty()),
+                Err(e) => {
+                    log::warn!("Error reading CPU topology, not pinning threads: {}", e);
+                    None
+                }
+            },
+        };
+
+        let threads = cpu_threads.unwrap_or_else(|| match (&pinned, cpu_cores) {
+            (Some(cpus), _) => cpus.len(),
+            (None, Some(cores)) => cores.0.len(),
+            (None, None) => num_cpus::get(),
+        });
+
+        let cpu_sets = match (&pinned, cpu_cores) {
+            (Some(cpus), _) => cpus.iter().map(|&cpu| vec![cpu]).collect(),
+            (None, Some(cores)) => vec![cores.0.clone()],
+            (None, None) => vec![vec![]],
+        };
+
+        let mut pinning = String::new();
+        if pinned.is_some() {
+            pinning += &format!(", {}", cpu_affinity);
+        }
+        if let Some(cores) = cpu_cores {
+            pinning += &format!(", cores {}", cores);
+        }
+
+        Ok(CpuMiner {
+            kernel_type: cpu_kernel.unwrap_or_default(),
+            calibrate: cpu_kernel.is_none(),
+            threads,
+            cpu_sets,
+            pinning,
+            priority,
+            max_usage,
+        })
     }
-}
 
-impl Miner for CpuMiner {
-    fn describe(&self) -> String {
-        format!("CPU [{}x {}]", self.threads, self.kernel_type)
+    /// Check whether a change can be applied to this miner
+    fn check(&self, change: Reconfigure) -> Result<(), ControlError> {
+        match change {
+            Reconfigure::Threads(0) => Err(ControlError::Invalid(
+                "at least one thread is needed".to_string(),
+            )),
+            Reconfigure::Threads(_) => Ok(()),
+            Reconfigure::CpuKernel(k) if !get_supported_kernels().contains(k) => Err(
+                ControlError::Invalid(format!("{} kernel isn't supported by this CPU", k)),
+            ),
+            Reconfigure::CpuKernel(_) => Ok(()),
+            Reconfigure::GpuRate(_) => Err(ControlError::Unsupported(change)),
+        }
     }
 
-    fn mine(self: Box<Self>, mut interface: MinerInterface) -> Result<(), MinerError> {
+    /// Apply a change which has passed [`check`](Self::check)
+    fn apply(&mut self, change: Reconfigure) {
+        match change {
+            Reconfigure::Threads(threads) => self.threads = threads,
+            Reconfigure::CpuKernel(kernel_type) => {
+                self.kernel_type = kernel_type;
+                self.calibrate = false;
+            }
+            Reconfigure::GpuRate(_) => unreachable!("rejected by check"),
+        }
+    }
+
+    /// Mine with the current configuration until told to stop, or until a
+    /// valid change is requested, which is returned to be applied
+    fn run_threads(&self, interface: &mut MinerInterface) -> Option<PendingChange> {
         let Self {
-            threads,
             kernel_type,
+            threads,
+            ref cpu_sets,
+            priority,
+            max_usage,
+            ..
         } = *self;
         // todo: investigate using evc to avoid locks, or parking_lot for better locks?
-        let hashes = AtomicU64::new(0);
-        let target = AtomicCell::new(interface.current_target().into_raw());
+        let hashes: Vec<AtomicU64> = (0..threads).map(|_| AtomicU64::new(0)).collect();
+        let shared = SharedState::new(interface.nonces().clone(), interface.epoch());
+        let first = interface.wait_for_target();
+        shared
+            .target
+            .store(WorkerTarget::new(first, interface.target_epoch()));
         let (sol_tx, sol_rx) = crossbeam::channel::bounded(1);
 
         // convert bindings to references to avoid lifetime/ownership complications
-        let hashes = &hashes;
-        let target = &target;
+        let hashes = &hashes[..];
+        let shared = &shared;
+        let target = &shared.target;
+        let paused = &shared.paused;
         let sol_tx = &sol_tx;
 
         crossbeam::scope(|s| {
             let address = interface.address();
-            let mut offset = Wrapping(rand::random());
 
-            for i in 0..threads {
+            for (i, hashes) in hashes.iter().enumerate() {
                 log::debug!("Spawning CPU miner thread {} using {:?}", i, kernel_type);
-                offset += Wrapping(std::u64::MAX / (threads as u64));
-                let ctx = Context::new(address, hashes, target, offset.0, sol_tx);
+                let cpus = &cpu_sets[i % cpu_sets.len()];
+                let ctx = Context::new(address, hashes, shared, sol_tx, max_usage);
                 s.builder()
                     .name(format!("CPU miner {}", i))
                     .spawn(move |_| {
-                        thread_priority::set_low_priority();
+                        if !cpus.is_empty() {
+                            if let Err(e) = topology::pin_current_thread(cpus) {
+                                log::warn!("Error pinning CPU miner thread {}: {}", i, e);
+                            }
+                        }
+
+                        priority.apply();
                         kernel_type.mine_with(ctx);
                     })
                     .unwrap();
             }
 
-            // management thread
+            // management thread, which owns the solution receiver so that
+            // mining threads blocked on sending a solution stop with it
             s.builder()
                 .name("CPU miner dispatch".to_string())
-                .spawn(|_| {
+                .spawn(move |_| {
                     let mut cycle_start = Instant::now();
 
                     loop {
-                        match sol_rx.recv_timeout(Duration::from_millis(1000)) {
+                        // check often, so threads preempted by a new target
+                        // don't wait long to be given it
+                        match sol_rx.recv_timeout(POLL_INTERVAL) {
                             Ok(s) => {
                                 if interface.report_solution(s).is_err() {
                                     target.store(None);
-                                    break;
+                                    return None;
                                 }
                             }
-                            Err(RecvTimeoutError::Disconnected) => break,
+                            Err(RecvTimeoutError::Disconnected) => return None,
                             Err(RecvTimeoutError::Timeout) => {}
                         }
 
+                        while let Some(change) = interface.pending_change() {
+                            match self.check(change.change()) {
+                                Ok(()) => {
+                                    target.store(None);
+                                    return Some(change);
+                                }
+                                Err(e) => change.reply(Err(e)),
+                            }
+                        }
+
                         match interface.current_target() {
                             CurrentTarget::Unchanged(_) => {}
-                            t => target.store(t.into_raw()),
+                            CurrentTarget::Paused => paused.store(true, Ordering::Relaxed),
+                            CurrentTarget::StopMining => {
+                                target.store(None);
+                                return None;
+                            }
+                            t => {
+                                paused.store(false, Ordering::Relaxed);
+                                target.store(WorkerTarget::new(t, interface.target_epoch()));
+                            }
+                        }
+
+                        if cycle_start.elapsed() < REPORT_INTERVAL {
+                            continue;
+                        }
+
+                        let stale = shared.stale_nanos.swap(0, Ordering::Relaxed);
+                        if stale > 0 {
+                            interface.report_stale_work(Duration::from_nanos(stale));
                         }
 
                         let cycle_time =
                             std::mem::replace(&mut cycle_start, Instant::now()).elapsed();
-                        let cycle_hashes = hashes.swap(0, Ordering::Relaxed);
-                        interface.report_speed(cycle_hashes, cycle_time);
+                        let thread_hashes: Vec<u64> = hashes
+                            .iter()
+                            .map(|h| h.swap(0, Ordering::Relaxed))
+                            .collect();
+                        if !paused.load(Ordering::Relaxed) {
+                            interface.report_speed(thread_hashes.iter().sum(), cycle_time);
+                            if thread_hashes.len() > 1 {
+                                interface.report_thread_speeds(thread_hashes, cycle_time);
+                            }
+                        }
                     }
                 })
-                .unwrap();
+                .unwrap()
+                .join()
+                .unwrap()
         })
-        .unwrap();
+        .unwrap()
+    }
+}
+
+impl Miner for CpuMiner {
+    fn describe(&self) -> String {
+        let kernel = if self.calibrate {
+            "auto".to_string()
+        } else {
+            self.kernel_type.to_string()
+        };
+
+        format!("CPU [{}x {}{}]", self.threads, kernel, self.pinning)
+    }
+
+    fn threads(&self) -> usize {
+        self.threads
+    }
+
+    fn mine(self: Box<Self>, mut interface: MinerInterface) -> Result<(), MinerError> {
+        let mut miner = *self;
+
+        if miner.calibrate {
+            interface.events().emit(EventKind::Calibrating);
+            miner.kernel_type = calibrate::fastest_supported_kernel();
+            miner.calibrate = false;
+            interface.events().emit(EventKind::Initializing {
+                description: miner.describe(),
+            });
+        }
+
+        while let Some(change) = miner.run_threads(&mut interface) {
+            miner.apply(change.change());
+            log::info!("CPU miner reconfigured: {}", miner.describe());
+            change.reply(Ok(()));
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::krist::address::Address;
+    use crate::krist::block::ShortHash;
+    use crate::miner::events::{EventBus, EventKind, MinerId};
+    use crate::miner::{watch, Target};
+
+    #[test]
+    fn test_new_target_preempts_threads() {
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+        let (target_tx, target_rx) = watch::channel();
+        let (sol_tx, _sol_rx) = futures::channel::mpsc::unbounded();
+        let address = Address::from_str("k5ztameslf").unwrap();
+        let interface = MinerInterface::new(address, bus.emitter(MinerId(0)), target_rx, sol_tx);
+
+        let miner = CpuMiner::new(&MinerConfig {
+            cpu_threads: Some(2),
+            cpu_kernel: Some(KernelType::Portable),
+            ..MinerConfig::default()
+        })
+        .unwrap();
+        let handle = std::thread::spawn(move || Box::new(miner).mine(interface));
+
+        for block in &["000000000000", "111111111111"] {
+            target_tx
+                .send(Target {
+                    work: 0,
+                    block: ShortHash::from_str(block).unwrap(),
+                })
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+        }
+
+        let stale = std::iter::from_fn(|| events.recv_timeout(Duration::from_secs(5)).ok())
+            .find_map(|e| match e.kind {
+                EventKind::StaleWork { elapsed } => Some(elapsed),
+                _ => None,
+            })
+            .unwrap();
+        // the mechanism is tested with the framework; here the threads only
+        // need to move on at some point
+        assert!(stale < Duration::from_secs(5), "stale for {:?}", stale);
+
+        drop(target_tx);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_unsupported_kernel_is_an_error() {
+        let unsupported = (!get_supported_kernels()).iter().next();
+
+        if let Some(kernel) = unsupported {
+            let result = CpuMiner::new(&MinerConfig {
+                cpu_kernel: Some(kernel),
+                ..MinerConfig::default()
+            });
+            assert!(matches!(result, Err(MinerError::UnsupportedKernel(k)) if k == kernel));
+        }
+    }
+
+    #[test]
+    fn test_calibrates_when_mining_starts() {
+        // creating the miner doesn't measure anything, so factories stay fast
+        let miner = CpuMiner::new(&MinerConfig {
+            cpu_threads: Some(1),
+            ..MinerConfig::default()
+        })
+        .unwrap();
+        assert!(miner.calibrate);
+        assert_eq!(miner.describe(), "CPU [1x auto]");
+    }
+}
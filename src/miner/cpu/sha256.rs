@@ -0,0 +1,147 @@
+//! Scalar SHA-256 building blocks shared by the CPU kernels
+
+/// SHA-256 round constants
+pub const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 initial state
+pub const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Perform round `i` of the compression function with message word `w`
+#[inline(always)]
+pub fn round(state: &mut [u32; 8], i: usize, w: u32) {
+    let [a, b, c, d, e, f, g, h] = *state;
+
+    let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+    let ch = g ^ (e & (f ^ g));
+    let t1 = h
+        .wrapping_add(s1)
+        .wrapping_add(ch)
+        .wrapping_add(K[i])
+        .wrapping_add(w);
+
+    let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+    let maj = (a & b) | (c & (a | b));
+    let t2 = s0.wrapping_add(maj);
+
+    *state = [t1.wrapping_add(t2), a, b, c, d.wrapping_add(t1), e, f, g];
+}
+
+/// The σ0 function of the message schedule
+#[inline(always)]
+pub fn sigma0(x: u32) -> u32 {
+    x.rotate_right(7) ^ x.rotate_right(18) ^ (x >> 3)
+}
+
+/// The σ1 function of the message schedule
+#[inline(always)]
+pub fn sigma1(x: u32) -> u32 {
+    x.rotate_right(17) ^ x.rotate_right(19) ^ (x >> 10)
+}
+
+/// Compute message schedule word `i` (at least 16) from the previous words
+#[inline(always)]
+pub fn expand(w: &[u32], i: usize) -> u32 {
+    w[i - 16]
+        .wrapping_add(sigma0(w[i - 15]))
+        .wrapping_add(w[i - 7])
+        .wrapping_add(sigma1(w[i - 2]))
+}
+
+/// Hash state precomputed from the parts of a krist hash input that stay the
+/// same for a whole block.
+///
+/// The address and short hash fill the first 22 bytes, so message words 0-4
+/// are constant, and words 9-15 only hold padding. The first five rounds, and
+/// message schedule words 16-19, don't depend on the nonce at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Midstate {
+    /// The state after the first 4 rounds, for kernels processing rounds in
+    /// groups of 4
+    pub state4: [u32; 8],
+
+    /// The state after the first 5 rounds
+    pub state5: [u32; 8],
+
+    /// Message schedule words 16-19
+    pub schedule: [u32; 4],
+}
+
+impl Midstate {
+    /// The number of leading message words which don't depend on the nonce
+    pub const CONSTANT_WORDS: usize = 5;
+
+    /// Precompute the midstate for a padded block, given as big-endian words
+    pub fn new(words: &[u32; 16]) -> Self {
+        let mut state = IV;
+        for (i, &w) in words.iter().enumerate().take(4) {
+            round(&mut state, i, w);
+        }
+
+        let state4 = state;
+        round(&mut state, 4, words[4]);
+
+        let mut w = [0; 20];
+        w[..16].copy_from_slice(words);
+        for i in 16..20 {
+            w[i] = expand(&w, i);
+        }
+
+        let mut schedule = [0; 4];
+        schedule.copy_from_slice(&w[16..]);
+
+        Self {
+            state4,
+            state5: state,
+            schedule,
+        }
+    }
+}
+
+/// Message schedule terms precomputed from the high nonce characters.
+///
+/// The last characters of the nonce fill message words 7 and 8, which only
+/// change once every `2^36` nonces, so every term of schedule words 22-24
+/// except the σ1 of an earlier expanded word can be computed ahead of time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HighNonceTerms {
+    /// The constant terms of message schedule words 22-24
+    pub partial: [u32; 3],
+}
+
+impl HighNonceTerms {
+    /// Precompute the terms for a padded block, given as big-endian words,
+    /// and its midstate
+    pub fn new(words: &[u32; 16], midstate: &Midstate) -> Self {
+        let [w16, w17, ..] = midstate.schedule;
+
+        Self {
+            partial: [
+                sigma0(words[7]).wrapping_add(words[15]),
+                words[7].wrapping_add(sigma0(words[8])).wrapping_add(w16),
+                words[8].wrapping_add(sigma0(words[9])).wrapping_add(w17),
+            ],
+        }
+    }
+
+    /// Finish expanding message schedule words 22-24 into `w`, which must
+    /// hold words 0-21
+    #[inline(always)]
+    pub fn expand(&self, w: &mut [u32]) {
+        w[22] = self.partial[0]
+            .wrapping_add(w[6])
+            .wrapping_add(sigma1(w[20]));
+        w[23] = self.partial[1].wrapping_add(sigma1(w[21]));
+        w[24] = self.partial[2].wrapping_add(sigma1(w[22]));
+    }
+}
@@ -0,0 +1,360 @@
+//! CPU topology, and pinning mining threads to CPUs
+
+use itertools::Itertools;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_to_string;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The number of CPUs a thread can be pinned to, which CPU numbers must be
+/// below
+#[cfg(target_os = "linux")]
+pub const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+
+/// The number of CPUs a thread can be pinned to, which CPU numbers must be
+/// below
+#[cfg(not(target_os = "linux"))]
+pub const MAX_CPUS: usize = 1024;
+
+/// A list of logical CPU numbers, written like `0-7,16-23`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuList(pub Vec<usize>);
+
+/// An error caused by an invalid CPU list
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid CPU list: {0:?} (expected CPU numbers below {max} like `0-7,16-23`)", max = MAX_CPUS)]
+pub struct InvalidCpuList(String);
+
+impl FromStr for CpuList {
+    type Err = InvalidCpuList;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCpuList(s.to_string());
+        let mut cpus = BTreeSet::new();
+
+        for range in s.trim().split(',').filter(|r| !r.is_empty()) {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => (range, range),
+            };
+
+            let start: usize = start.trim().parse().map_err(|_| invalid())?;
+            let end: usize = end.trim().parse().map_err(|_| invalid())?;
+
+            if start > end || end >= MAX_CPUS {
+                return Err(invalid());
+            }
+
+            cpus.extend(start..=end);
+        }
+
+        Ok(Self(cpus.into_iter().collect()))
+    }
+}
+
+impl Display for CpuList {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // group consecutive CPUs into ranges
+        let ranges = self
+            .0
+            .iter()
+            .enumerate()
+            .group_by(|&(i, &cpu)| cpu as isize - i as isize);
+
+        let ranges = ranges.into_iter().map(|(_, mut group)| {
+            let (_, first) = group.next().unwrap();
+            match group.last() {
+                Some((_, last)) => format!("{}-{}", first, last),
+                None => first.to_string(),
+            }
+        });
+
+        write!(f, "{}", ranges.format(","))
+    }
+}
+
+/// How to pin CPU mining threads to CPUs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AffinityPolicy {
+    /// Don't pin threads to individual CPUs
+    #[default]
+    None,
+
+    /// Pin each thread to a different physical core, leaving SMT siblings
+    /// unused
+    OnePerCore,
+
+    /// Pin each thread to a different CPU, alternating between NUMA nodes and
+    /// using every physical core before any SMT siblings
+    Spread,
+}
+
+/// An error caused by parsing an unknown affinity policy
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid affinity policy: {0} (expected none, one-per-core or spread)")]
+pub struct InvalidAffinityPolicy(String);
+
+impl FromStr for AffinityPolicy {
+    type Err = InvalidAffinityPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "none" => Self::None,
+            "one-per-core" => Self::OnePerCore,
+            "spread" => Self::Spread,
+            s => return Err(InvalidAffinityPolicy(s.to_string())),
+        })
+    }
+}
+
+impl Display for AffinityPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::OnePerCore => "one-per-core",
+            Self::Spread => "spread",
+        })
+    }
+}
+
+/// A logical CPU, as seen by the operating system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogicalCpu {
+    pub id: usize,
+    pub package: usize,
+    pub core: usize,
+    pub node: usize,
+}
+
+/// The logical CPUs in the system, and how they share cores and NUMA nodes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    pub cpus: Vec<LogicalCpu>,
+}
+
+impl Topology {
+    /// Read the topology from a Linux sysfs mounted at `root`
+    pub fn read(root: &Path) -> io::Result<Self> {
+        let read_list = |path: &Path| -> io::Result<Vec<usize>> {
+            read_to_string(path)?
+                .parse::<CpuList>()
+                .map(|l| l.0)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+
+        let read_number = |path: &Path| -> io::Result<usize> {
+            read_to_string(path)?
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+
+        // NUMA nodes aren't listed on systems without NUMA support
+        let node_dir = root.join("devices/system/node");
+        let mut nodes = BTreeMap::new();
+        if let Ok(online) = read_list(&node_dir.join("online")) {
+            for node in online {
+                for cpu in read_list(&node_dir.join(format!("node{}/cpulist", node)))? {
+                    nodes.insert(cpu, node);
+                }
+            }
+        }
+
+        let cpu_dir = root.join("devices/system/cpu");
+        let cpus = read_list(&cpu_dir.join("online"))?
+            .into_iter()
+            .map(|id| {
+                let topology = cpu_dir.join(format!("cpu{}/topology", id));
+
+                Ok(LogicalCpu {
+                    id,
+                    package: read_number(&topology.join("physical_package_id"))?,
+                    core: read_number(&topology.join("core_id"))?,
+                    node: nodes.get(&id).copied().unwrap_or(0),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self { cpus })
+    }
+
+    /// Read the topology of this system
+    pub fn current() -> io::Result<Self> {
+        Self::read(Path::new("/sys"))
+    }
+
+    /// Get the CPUs to pin threads to for the given policy, in order, only
+    /// using CPUs from `allowed` if given. Returns `None` if threads shouldn't
+    /// be pinned to individual CPUs.
+    pub fn assign(&self, policy: AffinityPolicy, allowed: Option<&CpuList>) -> Option<Vec<usize>> {
+        let cpus = self
+            .cpus
+            .iter()
+            .filter(|c| allowed.is_none_or(|a| a.0.contains(&c.id)));
+
+        // index each CPU by how many SMT siblings come before it on its core
+        let mut seen = BTreeMap::new();
+        let cpus = cpus.map(|c| {
+            let sibling = seen.entry((c.package, c.core)).or_insert(0);
+            *sibling += 1;
+            (*sibling - 1, c)
+        });
+
+        match policy {
+            AffinityPolicy::None => None,
+            AffinityPolicy::OnePerCore => Some(
+                cpus.filter(|&(sibling, _)| sibling == 0)
+                    .map(|(_, c)| c.id)
+                    .collect(),
+            ),
+            AffinityPolicy::Spread => {
+                // within each node, take the first thread of every core
+                // before any siblings, then alternate between nodes
+                let by_node = cpus
+                    .sorted_by_key(|&(sibling, c)| (c.node, sibling, c.id))
+                    .group_by(|&(_, c)| c.node);
+
+                let nodes: Vec<Vec<usize>> = by_node
+                    .into_iter()
+                    .map(|(_, cpus)| cpus.map(|(_, c)| c.id).collect())
+                    .collect();
+
+                Some(
+                    (0..nodes.iter().map(Vec::len).max().unwrap_or(0))
+                        .flat_map(|i| nodes.iter().filter_map(move |n| n.get(i).copied()))
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+/// Restrict the current thread to run on the given CPUs
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu >= MAX_CPUS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("CPU {} is out of range", cpu),
+                ));
+            }
+
+            libc::CPU_SET(cpu, &mut set);
+        }
+
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Restrict the current thread to run on the given CPUs
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "thread affinity is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    /// Create a fake sysfs for 2 NUMA nodes with 2 cores each, and 2 SMT
+    /// threads per core numbered like Linux does: CPUs 0-3 are the first
+    /// thread of each core, and 4-7 their siblings.
+    fn fake_sysfs() -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!("kristforge-sysfs-{}", rand::random::<u64>()));
+        let cpu_dir = root.join("devices/system/cpu");
+        let node_dir = root.join("devices/system/node");
+
+        create_dir_all(&cpu_dir).unwrap();
+        write(cpu_dir.join("online"), "0-7\n").unwrap();
+
+        for cpu in 0..8 {
+            let topology = cpu_dir.join(format!("cpu{}/topology", cpu));
+            create_dir_all(&topology).unwrap();
+            write(
+                topology.join("physical_package_id"),
+                format!("{}\n", cpu % 4 / 2),
+            )
+            .unwrap();
+            write(topology.join("core_id"), format!("{}\n", cpu % 2)).unwrap();
+        }
+
+        for (node, cpus) in ["0-1,4-5", "2-3,6-7"].iter().enumerate() {
+            create_dir_all(node_dir.join(format!("node{}", node))).unwrap();
+            write(node_dir.join(format!("node{}/cpulist", node)), cpus).unwrap();
+        }
+        write(node_dir.join("online"), "0-1\n").unwrap();
+
+        root
+    }
+
+    #[test]
+    fn test_cpu_list() {
+        assert_eq!(
+            CpuList::from_str("0-3,8,10-11"),
+            Ok(CpuList(vec![0, 1, 2, 3, 8, 10, 11]))
+        );
+        assert_eq!(CpuList::from_str("5\n"), Ok(CpuList(vec![5])));
+        assert_eq!(
+            CpuList::from_str("0-3,8,10-11").unwrap().to_string(),
+            "0-3,8,10-11"
+        );
+        assert!(CpuList::from_str("3-1").is_err());
+        assert!(CpuList::from_str("a-b").is_err());
+
+        // CPUs which can't be pinned are rejected without expanding the range
+        assert!(CpuList::from_str(&(MAX_CPUS - 1).to_string()).is_ok());
+        assert!(CpuList::from_str(&MAX_CPUS.to_string()).is_err());
+        assert!(CpuList::from_str("0-99999999999").is_err());
+    }
+
+    #[test]
+    fn test_read_topology() {
+        let root = fake_sysfs();
+        let topology = Topology::read(&root).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+
+        assert_eq!(topology.cpus.len(), 8);
+        assert_eq!(
+            topology.cpus[6],
+            LogicalCpu {
+                id: 6,
+                package: 1,
+                core: 0,
+                node: 1
+            }
+        );
+
+        assert_eq!(topology.assign(AffinityPolicy::None, None), None);
+        assert_eq!(
+            topology.assign(AffinityPolicy::OnePerCore, None),
+            Some(vec![0, 1, 2, 3])
+        );
+        assert_eq!(
+            topology.assign(AffinityPolicy::Spread, None),
+            Some(vec![0, 2, 1, 3, 4, 6, 5, 7])
+        );
+
+        let allowed = CpuList::from_str("0-1,4-5").unwrap();
+        assert_eq!(
+            topology.assign(AffinityPolicy::OnePerCore, Some(&allowed)),
+            Some(vec![0, 1])
+        );
+        assert_eq!(
+            topology.assign(AffinityPolicy::Spread, Some(&allowed)),
+            Some(vec![0, 1, 4, 5])
+        );
+    }
+}
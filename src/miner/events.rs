@@ -0,0 +1,294 @@
+//! Structured events published by miners
+//!
+//! Every miner publishes [`Event`]s into an [`EventBus`] through its
+//! [`MinerInterface`](super::interface::MinerInterface). Anything interested
+//! in miner status - a UI, logging, metrics or tests - subscribes to the bus
+//! independently of the others.
+
+use super::pause::PauseReason;
+use super::Target;
+use crate::krist::nonce::Nonce;
+use crossbeam::channel::{Receiver, Sender};
+use itertools::Itertools;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Identifies a miner within a mining session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MinerId(pub usize);
+
+impl Display for MinerId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "miner #{}", self.0)
+    }
+}
+
+/// Something that happened to a miner
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// The miner has been created and is waiting for its first target
+    Initializing { description: String },
+
+    /// The miner is measuring its options to choose the fastest, before it
+    /// starts mining. It publishes [`EventKind::Initializing`] again with its
+    /// new description once done.
+    Calibrating,
+
+    /// The miner computed `hashes` hashes over the period `elapsed`
+    Hashrate { hashes: u64, elapsed: Duration },
+
+    /// The hashes computed by each of the miner's threads over the period
+    /// `elapsed`, for miners with more than one thread. Published alongside
+    /// the combined [`EventKind::Hashrate`].
+    ThreadHashrates { hashes: Vec<u64>, elapsed: Duration },
+
+    /// The miner kept working on an old target for `elapsed` after a new one
+    /// was sent, before it was preempted
+    StaleWork { elapsed: Duration },
+
+    /// The miner found a solution, which is about to be submitted
+    Solution { target: Target, nonce: Nonce },
+
+    /// The miner has paused for the given reasons
+    Paused { reasons: Vec<PauseReason> },
+
+    /// The miner has resumed after being paused
+    Resumed,
+
+    /// The miner failed with an error
+    Error(String),
+
+    /// The miner will be restarted after `delay`, having failed `failures`
+    /// times in a row
+    Restarting { delay: Duration, failures: usize },
+
+    /// The miner has stopped, and won't publish any more events
+    Stopped,
+}
+
+/// An event published by a miner
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub miner: MinerId,
+    pub time: Instant,
+    pub kind: EventKind,
+}
+
+impl EventKind {
+    /// The hashrate in hashes per second, if this is a hashrate sample
+    pub fn hashrate(&self) -> Option<f64> {
+        match self {
+            EventKind::Hashrate { hashes, elapsed } => Some(*hashes as f64 / elapsed.as_secs_f64()),
+            _ => None,
+        }
+    }
+
+    /// The hashrate of each thread in hashes per second, if this is a
+    /// per-thread hashrate sample
+    pub fn thread_hashrates(&self) -> Option<Vec<f64>> {
+        match self {
+            EventKind::ThreadHashrates { hashes, elapsed } => Some(
+                hashes
+                    .iter()
+                    .map(|&h| h as f64 / elapsed.as_secs_f64())
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// A thread is flagged as slow if it runs below this fraction of the median
+/// hashrate of its miner's threads
+pub const SLOW_THREAD_RATIO: f64 = 0.75;
+
+/// A summary of the hashrates of a miner's threads
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+
+    /// The indices of threads running far below their siblings
+    pub slow: Vec<usize>,
+}
+
+impl ThreadStats {
+    /// Summarize per-thread hashrates, in hashes per second
+    pub fn new(hashrates: &[f64]) -> Option<Self> {
+        if hashrates.is_empty() {
+            return None;
+        }
+
+        let mut sorted = hashrates.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let median = sorted[sorted.len() / 2];
+
+        Some(Self {
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            slow: hashrates
+                .iter()
+                .positions(|&h| h < median * SLOW_THREAD_RATIO)
+                .collect(),
+        })
+    }
+}
+
+/// A broadcast bus delivering every published event to every subscriber.
+///
+/// Each subscriber has its own unbounded queue, so publishing never blocks and
+/// a slow subscriber doesn't hold back the others.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe to all events published after this call. Dropping the
+    /// receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Publish an event to all current subscribers
+    pub fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Get an emitter publishing events for the given miner
+    pub fn emitter(&self, miner: MinerId) -> EventEmitter {
+        EventEmitter {
+            bus: self.clone(),
+            miner,
+        }
+    }
+}
+
+/// Publishes events for a single miner
+#[derive(Debug, Clone)]
+pub struct EventEmitter {
+    bus: EventBus,
+    miner: MinerId,
+}
+
+impl EventEmitter {
+    /// The miner this emitter publishes events for
+    pub fn miner(&self) -> MinerId {
+        self.miner
+    }
+
+    /// Publish an event that happened now
+    pub fn emit(&self, kind: EventKind) {
+        self.bus.publish(Event {
+            miner: self.miner,
+            time: Instant::now(),
+            kind,
+        });
+    }
+}
+
+/// Log every event received from a subscription, returning once the bus has
+/// been dropped
+pub fn log_events(events: Receiver<Event>) {
+    for Event { miner, kind, .. } in events {
+        match kind {
+            EventKind::Initializing { description } => {
+                log::info!("{} initializing: {}", miner, description)
+            }
+            EventKind::Calibrating => log::info!("{} calibrating", miner),
+            EventKind::StaleWork { elapsed } => {
+                log::debug!("{} spent {:?} on a stale target", miner, elapsed)
+            }
+            EventKind::Hashrate { .. } | EventKind::ThreadHashrates { .. } => {
+                log::trace!("{} {:?}", miner, kind)
+            }
+            EventKind::Solution { target, nonce } => log::info!(
+                "{} found solution for target {:?}: {:?} (hex: {:x?})",
+                miner,
+                target,
+                nonce,
+                nonce.as_bytes()
+            ),
+            EventKind::Paused { reasons } => log::info!("{} paused: {:?}", miner, reasons),
+            EventKind::Resumed => log::info!("{} resumed", miner),
+            EventKind::Error(e) => log::error!("{} failed: {}", miner, e),
+            EventKind::Restarting { delay, failures } => log::info!(
+                "{} restarting in {:?} after {} failures",
+                miner,
+                delay,
+                failures
+            ),
+            EventKind::Stopped => log::info!("{} stopped", miner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_broadcast_to_all_subscribers() {
+        let bus = EventBus::new();
+        let a = bus.subscribe();
+        let b = bus.subscribe();
+
+        bus.emitter(MinerId(3)).emit(EventKind::Stopped);
+
+        for rx in &[a, b] {
+            let event = rx.try_recv().unwrap();
+            assert_eq!(event.miner, MinerId(3));
+            assert_eq!(event.kind, EventKind::Stopped);
+        }
+    }
+
+    #[test]
+    fn test_dropped_subscribers_are_removed() {
+        let bus = EventBus::new();
+        let a = bus.subscribe();
+        drop(bus.subscribe());
+
+        bus.emitter(MinerId(0)).emit(EventKind::Stopped);
+
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        assert!(a.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_hashrate() {
+        let kind = EventKind::Hashrate {
+            hashes: 500,
+            elapsed: Duration::from_millis(250),
+        };
+
+        assert_eq!(kind.hashrate(), Some(2000.));
+        assert_eq!(EventKind::Stopped.hashrate(), None);
+    }
+
+    #[test]
+    fn test_thread_stats() {
+        let kind = EventKind::ThreadHashrates {
+            hashes: vec![1000, 1100, 400, 900],
+            elapsed: Duration::from_millis(500),
+        };
+
+        let stats = ThreadStats::new(&kind.thread_hashrates().unwrap()).unwrap();
+        assert_eq!(stats.min, 800.);
+        assert_eq!(stats.max, 2200.);
+        assert_eq!(stats.mean, 1700.);
+        assert_eq!(stats.slow, vec![2]);
+        assert_eq!(ThreadStats::new(&[]), None);
+    }
+}
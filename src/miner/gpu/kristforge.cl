@@ -1,5 +1,43 @@
+// nonce encoding - NONCE_LENGTH, NONCE_BITS_PER_CHAR and NONCE_CHAR_OFFSET are
+// defined by the host to match `krist::nonce::Nonce`
+#define NONCE_CHAR(id, i) ((((id) >> ((i) * NONCE_BITS_PER_CHAR)) & ((1 << NONCE_BITS_PER_CHAR) - 1)) + NONCE_CHAR_OFFSET)
+
+// vector types - each work item hashes VECSIZE consecutive nonces, one per
+// vector lane. VECSIZE is defined by the host as 1, 2, 4 or 8.
+#if VECSIZE == 1
+typedef uint vuint;
+typedef ulong vulong;
+#define LANE_OFFSETS ((vulong)(0))
+#define CONVERT_VUINT convert_uint
+#define CONVERT_VULONG convert_ulong
+#define STORE_VULONG(v, p) ((p)[0] = (v))
+#elif VECSIZE == 2
+typedef uint2 vuint;
+typedef ulong2 vulong;
+#define LANE_OFFSETS ((vulong)(0, 1))
+#define CONVERT_VUINT convert_uint2
+#define CONVERT_VULONG convert_ulong2
+#define STORE_VULONG(v, p) vstore2((v), 0, (p))
+#elif VECSIZE == 4
+typedef uint4 vuint;
+typedef ulong4 vulong;
+#define LANE_OFFSETS ((vulong)(0, 1, 2, 3))
+#define CONVERT_VUINT convert_uint4
+#define CONVERT_VULONG convert_ulong4
+#define STORE_VULONG(v, p) vstore4((v), 0, (p))
+#elif VECSIZE == 8
+typedef uint8 vuint;
+typedef ulong8 vulong;
+#define LANE_OFFSETS ((vulong)(0, 1, 2, 3, 4, 5, 6, 7))
+#define CONVERT_VUINT convert_uint8
+#define CONVERT_VULONG convert_ulong8
+#define STORE_VULONG(v, p) vstore8((v), 0, (p))
+#else
+#error "VECSIZE must be 1, 2, 4 or 8"
+#endif
+
 // right rotate macro
-#define RR(x, y) rotate((uint)(x), -(uint)(y))
+#define RR(x, y) rotate((vuint)(x), (vuint)(32 - (y)))
 
 // sha256 macros
 #define CH(x, y, z) bitselect((z),(y),(x))
@@ -35,27 +73,31 @@ kernel void mine(
 	constant const uchar *input,    // address + prev block - 22 bytes
 	const ulong work,               // target work
 	const ulong offset,             // id offset
-	global uchar *solution          // solution nonce - 11 bytes
+	global uchar *solution          // solution nonce - NONCE_LENGTH bytes
 ) {
-	// initialize hash input array
-	uchar text[64] = { 0 };
+	// initialize hash input array, with one byte per lane
+	vuint text[64];
+
+#pragma unroll
+	for (int i = 0; i < 64; i++) text[i] = (vuint)(0);
 
 	// fill first 22 bytes of hash input
 #pragma unroll
-	for (int i = 0; i < 22; i++) text[i] = input[i];
+	for (int i = 0; i < 22; i++) text[i] = (vuint)(input[i]);
 
-	// expand id into next 11 bytes
-	ulong id = get_global_id(0) + offset;
+	// expand the id of each lane into the next NONCE_LENGTH bytes
+	ulong base = get_global_id(0) * VECSIZE + offset;
+	vulong id = (vulong)(base) + LANE_OFFSETS;
 
 #pragma unroll
-	for (int i = 0; i < 11; i++) { text[i + 22] = ((id >> (i * 6)) & 0x3f) + 32; }
+	for (int i = 0; i < NONCE_LENGTH; i++) { text[i + 22] = CONVERT_VUINT(NONCE_CHAR(id, i)); }
 
-	// padding - digest input is 33 bytes
-	text[33] = 0x80;
-	text[62] = ((33 * 8) >> 8) & 0xff;
-	text[63] =  (33 * 8)       & 0xff;
+	// padding - digest input is 22 + NONCE_LENGTH bytes
+	text[22 + NONCE_LENGTH] = (vuint)(0x80);
+	text[62] = (vuint)((((22 + NONCE_LENGTH) * 8) >> 8) & 0xff);
+	text[63] = (vuint)( ((22 + NONCE_LENGTH) * 8)       & 0xff);
 
-	uint a, b, c, d, e, f, g, h, t1, t2, m[64];
+	vuint a, b, c, d, e, f, g, h, t1, t2, m[64];
 
 	// message extension
 #pragma unroll
@@ -99,19 +141,23 @@ kernel void mine(
 	a += H0;
 	b += H1;
 
-	ulong score =   (((ulong)(a >> 24)) & 0xff) << 40 |
-					(((ulong)(a >> 16)) & 0xff) << 32 |
-					(((ulong)(a >>  8)) & 0xff) << 24 |
-					(((ulong)(a      )) & 0xff) << 16 |
-					(((ulong)(b >> 24)) & 0xff) << 8  |
-					(((ulong)(b >> 16)) & 0xff);
+	vulong score = CONVERT_VULONG(a) << 16 | CONVERT_VULONG(b >> 16);
+
+	ulong scores[VECSIZE];
+	STORE_VULONG(score, scores);
+
+	for (int lane = 0; lane < VECSIZE; lane++) {
+		if (scores[lane] <= work) {
+			// solution found!
+			// write the lane's nonce to the solution buffer
+			ulong lane_id = base + lane;
 
-	if (score <= work) {
-		// solution found!
-		// copy nonce to solution buffer
 #pragma unroll
-		for (int i = 0; i < 11; i++) {
-			solution[i] = text[i + 22];
+			for (int i = 0; i < NONCE_LENGTH; i++) {
+				solution[i] = NONCE_CHAR(lane_id, i);
+			}
+
+			break;
 		}
 	}
 }
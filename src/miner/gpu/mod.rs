@@ -1,6 +1,19 @@
+//! OpenCL miner
+
+pub mod select;
+pub mod tune;
+
 use super::MinerError;
+use crate::krist::address::Address;
+use crate::krist::nonce::Nonce;
+use crate::miner::control::{ControlError, Reconfigure};
+use crate::miner::events::EventKind;
+use crate::miner::gpu::select::{DeviceId, DeviceKind, DeviceKinds, PciBusId};
+use crate::miner::gpu::tune::KernelConfig;
 use crate::miner::interface::{CurrentTarget, MinerInterface};
-use crate::miner::{Miner, MinerConfig};
+use crate::miner::priority::Priority;
+use crate::miner::throttle::UsageLimit;
+use crate::miner::{Miner, MinerConfig, Target};
 use dynamic_ocl::buffer::flags::{DeviceReadOnly, DeviceWriteOnly, HostReadWrite, HostWriteOnly};
 use dynamic_ocl::buffer::Buffer;
 use dynamic_ocl::device::{Device, DeviceType};
@@ -11,32 +24,65 @@ use dynamic_ocl::queue::Queue;
 use dynamic_ocl::raw::{cl_device_info, cl_uchar, cl_uint, cl_ulong, CL_DEVICE_NOT_FOUND};
 use dynamic_ocl::util::OclInfo;
 use dynamic_ocl::{load_opencl, Error as OclError};
+use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::collections::HashSet;
+use std::convert::{TryFrom, TryInto};
 use std::ffi::CString;
 use std::fmt::{self, Display, Formatter};
-use std::time::Instant;
+use std::hash::Hash;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 /// OpenCL kernel source
 const OCL_SRC: &str = include_str!("kristforge.cl");
 
 /// An OpenCL device that can be used for mining
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MiningDevice {
     device: Device,
+    id: DeviceId,
+    kind: DeviceKind,
     name: String,
+    platform_name: String,
     compute_units: cl_uint,
     clock_freq: cl_uint,
 }
 
+impl MiningDevice {
+    /// The identifiers used to select this device
+    pub fn id(&self) -> &DeviceId {
+        &self.id
+    }
+
+    /// The human-readable name of this device
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether this is a GPU, CPU or accelerator
+    pub fn kind(&self) -> DeviceKind {
+        self.kind
+    }
+}
+
 impl Display for MiningDevice {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
             f,
-            "OpenCL device \"{name}\":\n\
+            "OpenCL device #{index} \"{name}\":\n\
+            \tID: {id}\n\
+            \tType: {kind}\n\
+            \tPlatform: {platform}:{device} ({platform_name})\n\
             \tCompute units: {compute_units}\n\
             \tClock frequency: {clock_freq}",
+            index = self.id.index,
             name = self.name,
+            id = self.id,
+            kind = self.kind,
+            platform = self.id.platform,
+            device = self.id.device,
+            platform_name = self.platform_name,
             compute_units = self.compute_units,
             clock_freq = self.clock_freq
         )
@@ -56,8 +102,75 @@ fn device_human_name(device: Device) -> Result<CString, OclError> {
     }
 }
 
-/// Get compatible OpenCL devices
-pub fn get_opencl_devices() -> Result<Vec<MiningDevice>, MinerError> {
+/// Get the PCI address of a device, using whichever of the KHR, AMD or NVIDIA
+/// extensions reporting it is supported
+fn device_pci_bus_id(device: Device) -> Option<PciBusId> {
+    const CL_DEVICE_PCI_BUS_INFO_KHR: cl_device_info = 0x410F;
+    const CL_DEVICE_TOPOLOGY_AMD: cl_device_info = 0x4037;
+    const CL_DEVICE_TOPOLOGY_TYPE_PCIE_AMD: u32 = 1;
+    const CL_DEVICE_PCI_BUS_ID_NV: cl_device_info = 0x4008;
+    const CL_DEVICE_PCI_SLOT_ID_NV: cl_device_info = 0x4009;
+    const CL_DEVICE_PCI_DOMAIN_ID_NV: cl_device_info = 0x400A;
+
+    let extensions = device.extensions().ok()?.to_string_lossy().into_owned();
+    let word = |raw: &[u8], i: usize| u32::from_ne_bytes(raw[i * 4..i * 4 + 4].try_into().unwrap());
+
+    let khr = || {
+        // four cl_uints: domain, bus, device and function
+        let raw: Vec<u8> = device.get_info(CL_DEVICE_PCI_BUS_INFO_KHR).ok()?;
+        if raw.len() != 16 {
+            return None;
+        }
+
+        Some(PciBusId {
+            domain: word(&raw, 0),
+            bus: word(&raw, 1) as u8,
+            device: word(&raw, 2) as u8,
+            function: word(&raw, 3) as u8,
+        })
+    };
+
+    let amd = || {
+        // a cl_uint type, 17 unused bytes, then the bus, device and function
+        let raw: Vec<u8> = device.get_info(CL_DEVICE_TOPOLOGY_AMD).ok()?;
+        if raw.len() != 24 || word(&raw, 0) != CL_DEVICE_TOPOLOGY_TYPE_PCIE_AMD {
+            return None;
+        }
+
+        Some(PciBusId {
+            domain: 0,
+            bus: raw[21],
+            device: raw[22],
+            function: raw[23],
+        })
+    };
+
+    let nv = || {
+        let bus: cl_uint = device.get_info(CL_DEVICE_PCI_BUS_ID_NV).ok()?;
+        let slot: cl_uint = device.get_info(CL_DEVICE_PCI_SLOT_ID_NV).ok()?;
+
+        Some(PciBusId {
+            domain: device.get_info(CL_DEVICE_PCI_DOMAIN_ID_NV).unwrap_or(0),
+            bus: bus as u8,
+            device: (slot >> 3) as u8,
+            function: (slot & 7) as u8,
+        })
+    };
+
+    let queries: [(&str, &dyn Fn() -> Option<PciBusId>); 3] = [
+        ("cl_khr_pci_bus_info", &khr),
+        ("cl_amd_device_attribute_query", &amd),
+        ("cl_nv_device_attribute_query", &nv),
+    ];
+
+    queries
+        .iter()
+        .filter(|(extension, _)| extensions.contains(extension))
+        .find_map(|(_, query)| query())
+}
+
+/// Get compatible OpenCL devices of the given kinds, in a consistent order
+pub fn get_opencl_devices(kinds: DeviceKinds) -> Result<Vec<MiningDevice>, MinerError> {
     match load_opencl() {
         Err(e) => {
             eprintln!(
@@ -67,7 +180,7 @@ pub fn get_opencl_devices() -> Result<Vec<MiningDevice>, MinerError> {
             Ok(vec![])
         }
         Ok(_) => {
-            let mut devices = HashSet::new();
+            let mut devices = vec![];
 
             let platforms = match Platform::get_platforms() {
                 Err(e) => {
@@ -78,24 +191,34 @@ pub fn get_opencl_devices() -> Result<Vec<MiningDevice>, MinerError> {
                 Ok(p) => p,
             };
 
-            for platform in platforms {
-                let platform_devices = match platform.get_devices(DeviceType::GPU) {
+            for platform in &platforms {
+                devices.push(match platform.get_devices(DeviceType::ALL) {
                     Err(OclError::ApiError(e)) if e.code() == CL_DEVICE_NOT_FOUND => vec![],
                     e => e?,
-                };
-                devices.extend(platform_devices.into_iter());
+                });
             }
 
             let mut wrapped = vec![];
 
-            for device in devices {
+            for (index, platform_index, device_index, device) in
+                number_devices(&devices, kinds, |d| Ok(DeviceKind::of(d.device_type()?)))?
+            {
+                let platform = platforms[platform_index];
                 let name = device_human_name(device)?.to_string_lossy().into_owned();
                 let compute_units = device.max_compute_units()?;
                 let clock_freq = device.max_clock_frequency()?;
 
                 let device = MiningDevice {
                     device,
+                    id: DeviceId {
+                        index,
+                        platform: platform_index,
+                        device: device_index,
+                        pci_bus_id: device_pci_bus_id(device),
+                    },
+                    kind: DeviceKind::of(device.device_type()?),
                     name,
+                    platform_name: platform.name()?.to_string_lossy().into_owned(),
                     compute_units,
                     clock_freq,
                 };
@@ -110,6 +233,36 @@ pub fn get_opencl_devices() -> Result<Vec<MiningDevice>, MinerError> {
     }
 }
 
+/// Number the devices of each platform like [`DeviceId`] does, counting every
+/// device so that identifiers don't depend on the kinds selected, then keep
+/// the devices of the given kinds. Devices listed by several platforms are
+/// only counted the first time.
+fn number_devices<D: Copy + Eq + Hash>(
+    platforms: &[Vec<D>],
+    kinds: DeviceKinds,
+    kind_of: impl Fn(D) -> Result<DeviceKind, MinerError>,
+) -> Result<Vec<(usize, usize, usize, D)>, MinerError> {
+    let mut seen = HashSet::new();
+    let mut numbered = vec![];
+
+    for (platform_index, devices) in platforms.iter().enumerate() {
+        for (device_index, &device) in devices.iter().enumerate() {
+            if seen.insert(device) {
+                numbered.push((platform_index, device_index, device));
+            }
+        }
+    }
+
+    let mut selected = vec![];
+    for (index, (platform_index, device_index, device)) in numbered.into_iter().enumerate() {
+        if kinds.contains(kind_of(device)?) {
+            selected.push((index, platform_index, device_index, device));
+        }
+    }
+
+    Ok(selected)
+}
+
 type MinerKernel = Kernel<(
     Buffer<'static, HostWriteOnly, cl_uchar>,
     cl_ulong,
@@ -117,28 +270,173 @@ type MinerKernel = Kernel<(
     Buffer<'static, HostReadWrite, cl_uchar>,
 )>;
 
+/// The number of nonces hashed by each OpenCL work item, using vector types
+/// of this width: 1, 2, 4 or 8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u32", into = "u32")]
+pub struct VectorWidth(u32);
+
+impl VectorWidth {
+    pub const SCALAR: Self = Self(1);
+
+    /// Every supported width, narrowest first
+    pub const ALL: [Self; 4] = [Self(1), Self(2), Self(4), Self(8)];
+
+    /// The widest supported width no wider than the device's preferred width
+    pub fn for_device(device: Device) -> Result<Self, OclError> {
+        Ok(Self::from_preferred(device.preferred_vector_width_int()?))
+    }
+
+    /// The widest supported width no wider than `preferred`
+    pub fn from_preferred(preferred: u32) -> Self {
+        Self(
+            [8, 4, 2]
+                .iter()
+                .copied()
+                .find(|&w| w <= preferred)
+                .unwrap_or(1),
+        )
+    }
+
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+/// An error caused by an unsupported vector width
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid vector width {0:?}, expected 1, 2, 4 or 8")]
+pub struct InvalidVectorWidth(String);
+
+impl TryFrom<u32> for VectorWidth {
+    type Error = InvalidVectorWidth;
+
+    fn try_from(width: u32) -> Result<Self, Self::Error> {
+        match width {
+            1 | 2 | 4 | 8 => Ok(Self(width)),
+            _ => Err(InvalidVectorWidth(width.to_string())),
+        }
+    }
+}
+
+impl From<VectorWidth> for u32 {
+    fn from(width: VectorWidth) -> Self {
+        width.0
+    }
+}
+
+impl FromStr for VectorWidth {
+    type Err = InvalidVectorWidth;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .parse::<u32>()
+            .ok()
+            .and_then(|w| Self::try_from(w).ok())
+            .ok_or_else(|| InvalidVectorWidth(s.to_string()))
+    }
+}
+
+impl Display for VectorWidth {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The longest an OpenCL miner works on a target after a new one is sent, in
+/// seconds, by splitting dispatches into sub-ranges of about this length
+const PREEMPT_INTERVAL: f32 = 0.01;
+
+/// How often an OpenCL miner takes pending changes while paused or waiting
+/// for its first target
+const CHANGE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A miner using a single OpenCL device
 pub struct OclMiner {
     name: String,
+    kind: DeviceKind,
     queue: Queue,
     kernel: MinerKernel,
+    vector_width: VectorWidth,
+    local_size: Option<usize>,
+    initial_work_size: usize,
     max_work_size: usize,
     target_rate: f32,
+    priority: Priority,
+    max_usage: UsageLimit,
+    /// The device and configuration to tune when mining starts, if it has no
+    /// cached profile
+    tune: Option<(MiningDevice, MinerConfig)>,
 }
 
 impl OclMiner {
-    pub fn new(
-        MiningDevice { device, name, .. }: MiningDevice,
+    /// Compile the mining kernel for the given device using its cached
+    /// profile, or else the configured or preferred vector width. Devices
+    /// without a cached profile are tuned when mining starts, unless tuning is
+    /// disabled.
+    pub fn new(device: MiningDevice, config: &MinerConfig) -> Result<Self, MinerError> {
+        let cached = if config.no_gpu_tune {
+            None
+        } else {
+            tune::cached_kernel_config(&device, config)?
+        };
+
+        if let Some(kernel_config) = cached {
+            return Self::with_kernel_config(device, config, kernel_config);
+        }
+
+        let width = match config.gpu_vector_width {
+            Some(width) => width,
+            None => VectorWidth::for_device(device.device)?,
+        };
+
+        let mut miner =
+            Self::with_kernel_config(device.clone(), config, KernelConfig::untuned(width))?;
+        if !config.no_gpu_tune {
+            miner.tune = Some((device, config.clone()));
+        }
+
+        Ok(miner)
+    }
+
+    /// Compile the mining kernel for the given device with a specific
+    /// configuration
+    fn with_kernel_config(
+        MiningDevice {
+            device, name, kind, ..
+        }: MiningDevice,
         &MinerConfig {
             gpu_rate: target_rate,
             gpu_max_worksize: max_work_size,
+            gpu_priority: priority,
+            gpu_max_usage: max_usage,
             ..
         }: &MinerConfig,
+        KernelConfig {
+            vector_width,
+            local_size,
+            work_size,
+        }: KernelConfig,
     ) -> Result<Self, MinerError> {
-        log::info!("Initializing OpenCL miner on {}", name);
+        log::info!(
+            "Initializing OpenCL miner on {} with vector width {}, {} local work size and initial work size {}",
+            name,
+            vector_width,
+            local_size.map_or("driver-chosen".to_string(), |l| l.to_string()),
+            work_size
+        );
 
         let ctx = device.create_context()?;
         let queue = ctx.create_queue(device)?;
-        let program = ProgramBuilder::with_source(&ctx, &OCL_SRC).build()?;
+        let program = ProgramBuilder::with_source(&ctx, &OCL_SRC)
+            .opt(format!(
+                "-D NONCE_LENGTH={} -D NONCE_BITS_PER_CHAR={} -D NONCE_CHAR_OFFSET={} -D VECSIZE={}",
+                Nonce::LENGTH,
+                Nonce::BITS_PER_CHAR,
+                Nonce::CHAR_OFFSET,
+                vector_width
+            ))
+            .build()?;
 
         let build_log = program.build_info(device)?.log()?;
         let build_log = build_log.to_string_lossy();
@@ -156,7 +454,7 @@ impl OclMiner {
             .buffer_builder()
             .device_access::<DeviceWriteOnly>()
             .alloc_host_ptr()
-            .build_copying_slice(&[0u8; 11])?;
+            .build_copying_slice(&[0u8; Nonce::LENGTH])?;
 
         // kernel arg type checks can cause issues with some OpenCL drivers, so
         // we disable them in release mode
@@ -168,78 +466,190 @@ impl OclMiner {
 
         Ok(Self {
             name,
+            kind,
             queue,
             kernel,
+            vector_width,
+            local_size,
+            initial_work_size: min(work_size, max_work_size),
             max_work_size,
             target_rate,
+            priority,
+            max_usage,
+            tune: None,
         })
     }
+
+    /// Apply a change requested while mining. Only [`Reconfigure::GpuRate`]
+    /// applies to OpenCL miners, taking effect from the next dispatch.
+    fn reconfigure(&mut self, change: Reconfigure) -> Result<(), ControlError> {
+        match change {
+            Reconfigure::GpuRate(rate) if rate > 0. && rate.is_finite() => {
+                self.target_rate = rate;
+                Ok(())
+            }
+            Reconfigure::GpuRate(rate) => Err(ControlError::Invalid(format!(
+                "GPU rate must be positive, not {}",
+                rate
+            ))),
+            _ => Err(ControlError::Unsupported(change)),
+        }
+    }
+
+    /// Set the address for subsequent kernel executions
+    fn set_address(&mut self, address: &Address) -> Result<(), MinerError> {
+        self.queue
+            .buffer_cmd(&mut self.kernel.arguments().0)
+            .write(&address.as_bytes()[..])?;
+        Ok(())
+    }
+
+    /// Set the block and work for subsequent kernel executions
+    fn set_target(&mut self, target: Target) -> Result<(), MinerError> {
+        let (mut input, work, _, _) = self.kernel.arguments();
+        work.set(target.work)?;
+        self.queue
+            .buffer_cmd(&mut input)
+            .offset(10)
+            .write(target.block.into_hex().as_bytes())?;
+        Ok(())
+    }
+
+    /// Execute the kernel over `work_size` work items, hashing `work_size`
+    /// times the vector width nonces starting from `offset`, and returning a
+    /// solution if one was found
+    fn run(&mut self, offset: u64, work_size: usize) -> Result<Option<Nonce>, MinerError> {
+        self.kernel.arguments().2.set(offset)?;
+
+        // execute kernel, letting the driver choose the local work size if the
+        // work size isn't a multiple of the tuned one
+        self.queue
+            .kernel_cmd(&mut self.kernel)
+            .local_work_size(self.local_size.filter(|&l| work_size.is_multiple_of(l)))
+            .exec_ndrange(work_size)?;
+
+        // read output and check for solution
+        let mut solution = [0u8; Nonce::LENGTH];
+        self.queue
+            .buffer_cmd(&mut self.kernel.arguments().3)
+            .read(&mut solution)?;
+
+        if solution == [0u8; Nonce::LENGTH] {
+            return Ok(None);
+        }
+
+        // zero out solution buffer
+        self.queue
+            .buffer_cmd(&mut self.kernel.arguments().3)
+            .write(&[0; Nonce::LENGTH])?;
+
+        Ok(Some(Nonce::try_from(&solution[..])?))
+    }
+
+    /// The size of the sub-ranges a dispatch of `work_size` work items is
+    /// split into, rounded up to a multiple of the local work size
+    fn sub_range_size(&self, work_size: usize) -> usize {
+        let sub_ranges = (self.target_rate / PREEMPT_INTERVAL).ceil() as usize;
+        let size = work_size.div_ceil(sub_ranges.clamp(1, work_size));
+
+        match self.local_size {
+            Some(local_size) => size.next_multiple_of(local_size),
+            None => size,
+        }
+    }
 }
 
 impl Miner for OclMiner {
     fn describe(&self) -> String {
-        format!("GPU [{}]", self.name)
+        let kind = match self.kind {
+            DeviceKind::Gpu => "GPU",
+            DeviceKind::Cpu => "OpenCL CPU",
+            DeviceKind::Accelerator => "OpenCL accelerator",
+        };
+
+        match self.vector_width {
+            _ if self.tune.is_some() => format!("{} [{}, auto]", kind, self.name),
+            VectorWidth::SCALAR => format!("{} [{}]", kind, self.name),
+            width => format!("{} [{}, {}-wide]", kind, self.name, width),
+        }
     }
 
     fn mine(mut self: Box<Self>, mut interface: MinerInterface) -> Result<(), MinerError> {
-        // write the address into first part of input buffer
-        self.queue
-            .buffer_cmd(&mut self.kernel.arguments().0)
-            .write(&interface.address().as_bytes()[..])?;
+        self.priority.apply();
+
+        if let Some((device, config)) = self.tune.take() {
+            interface.events().emit(EventKind::Calibrating);
+            let kernel_config = tune::tuned_kernel_config(&device, &config)?;
+            *self = Self::with_kernel_config(device, &config, kernel_config)?;
+            interface.events().emit(EventKind::Initializing {
+                description: self.describe(),
+            });
+        }
 
-        let mut work_size = 1usize;
-        let mut offset = rand::random();
+        self.set_address(&interface.address())?;
 
-        let mut cycle_start = Instant::now();
+        let mut work_size = self.initial_work_size;
+        let mut throttle = self.max_usage.throttle();
+        let epoch = interface.epoch();
 
         loop {
-            // update miner target
-            match interface.current_target() {
-                CurrentTarget::StopMining => break,
-                CurrentTarget::New(t) => {
-                    let (mut input, work, _, _) = self.kernel.arguments();
-                    work.set(t.work)?;
-                    self.queue
-                        .buffer_cmd(&mut input)
-                        .offset(10)
-                        .write(t.block.into_hex().as_bytes())?;
-                }
-                CurrentTarget::Unchanged(_) => {}
-            };
+            while let Some(change) = interface.pending_change() {
+                let result = self.reconfigure(change.change());
+                change.reply(result);
+            }
 
-            // execute kernel
-            self.queue
-                .kernel_cmd(&mut self.kernel)
-                .exec_ndrange(work_size)?;
+            // update miner target, waiting while paused but still taking
+            // changes every so often
+            match interface.wait_for_target_timeout(CHANGE_POLL_INTERVAL) {
+                None => continue,
+                Some(CurrentTarget::StopMining) => break,
+                Some(CurrentTarget::New(t)) => self.set_target(t)?,
+                Some(CurrentTarget::Unchanged(_)) | Some(CurrentTarget::Paused) => {}
+            };
 
-            // read output and check for solution
-            let mut solution = [0u8; 11];
-            self.queue
-                .buffer_cmd(&mut self.kernel.arguments().3)
-                .read(&mut solution)?;
+            let cycle_start = Instant::now();
+            let lanes = self.vector_width.get() as u64;
+            let offset = interface.allocate_nonces(work_size as u64 * lanes).start;
+            let target_epoch = interface.target_epoch();
+
+            // split the dispatch into sub-ranges, stopping early if a new
+            // target arrives
+            let sub_range_size = self.sub_range_size(work_size);
+            let mut done = 0;
+            let mut preempted = false;
+
+            while done < work_size && !preempted {
+                let size = min(sub_range_size, work_size - done);
+
+                if let Some(solution) = self.run(offset + done as u64 * lanes, size)? {
+                    // solution found!
+                    if interface.report_solution(solution).is_err() {
+                        return Ok(());
+                    }
+                }
 
-            if solution != [0u8; 11] {
-                // solution found!
-                let solution = String::from_utf8(Vec::from(&solution[..])).expect("invalid nonce");
+                done += size;
 
-                if interface.report_solution(solution).is_err() {
-                    break;
+                if epoch.current() != target_epoch {
+                    interface.report_stale_work(epoch.started().elapsed());
+                    preempted = true;
                 }
-
-                // zero out solution buffer
-                self.queue
-                    .buffer_cmd(&mut self.kernel.arguments().3)
-                    .write(&[0; 11])?;
             }
 
-            let cycle_time = std::mem::replace(&mut cycle_start, Instant::now()).elapsed();
+            let cycle_time = cycle_start.elapsed();
+
+            // leave the device idle between dispatches if limited, counting
+            // the idle time towards the reported speed
+            if let Some(throttle) = &mut throttle {
+                throttle.throttle(cycle_time);
+            }
 
-            offset += work_size as u64;
-            self.kernel.arguments().2.set(offset)?;
-            interface.report_speed(work_size as u64, cycle_time);
+            interface.report_speed(done as u64 * lanes, cycle_start.elapsed());
 
-            // adjust work size for next execution
-            if cycle_time.as_secs_f32() < self.target_rate / 2.0 {
+            // adjust work size for next execution, unless it was cut short
+            if preempted {
+                continue;
+            } else if cycle_time.as_secs_f32() < self.target_rate / 2.0 {
                 work_size = min(self.max_work_size, work_size * 2);
             } else if cycle_time.as_secs_f32() > self.target_rate * 2.0 {
                 work_size = max(1, work_size / 2);
@@ -249,3 +659,181 @@ impl Miner for OclMiner {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::krist::block::ShortHash;
+    use crate::miner::events::{EventBus, MinerId};
+    use std::str::FromStr;
+    use structopt::StructOpt;
+
+    /// Get every available OpenCL device, including CPU devices such as POCL
+    /// on machines without a GPU. Panics if there are none and
+    /// `KRISTFORGE_REQUIRE_OPENCL` is set, so that CI can't pass without
+    /// testing the kernel.
+    pub(crate) fn test_devices() -> Vec<MiningDevice> {
+        let devices = get_opencl_devices(DeviceKinds::all()).unwrap();
+
+        if devices.is_empty() && std::env::var_os("KRISTFORGE_REQUIRE_OPENCL").is_some() {
+            panic!("no OpenCL devices found, but KRISTFORGE_REQUIRE_OPENCL is set");
+        }
+
+        devices
+    }
+
+    /// Run a test against every available OpenCL device
+    fn with_each_miner(test: impl Fn(OclMiner)) {
+        for device in test_devices() {
+            for width in &["1", "2", "4", "8"] {
+                let cfg =
+                    MinerConfig::from_iter(&["test", "--no-gpu-tune", "--gpu-vector-width", width]);
+                test(OclMiner::new(device.clone(), &cfg).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_device_ids_ignore_kinds() {
+        // two platforms listing a GPU, a CPU and an accelerator, with the CPU
+        // listed by both
+        let platforms = [vec!['g', 'c'], vec!['c', 'a', 'h']];
+        let kind_of = |d| {
+            Ok(match d {
+                'c' => DeviceKind::Cpu,
+                'a' => DeviceKind::Accelerator,
+                _ => DeviceKind::Gpu,
+            })
+        };
+
+        let gpus = number_devices(&platforms, DeviceKinds::default(), kind_of).unwrap();
+        assert_eq!(gpus, [(0, 0, 0, 'g'), (3, 1, 2, 'h')]);
+
+        let all = number_devices(&platforms, DeviceKinds::all(), kind_of).unwrap();
+        assert_eq!(
+            all,
+            [
+                (0, 0, 0, 'g'),
+                (1, 0, 1, 'c'),
+                (2, 1, 1, 'a'),
+                (3, 1, 2, 'h')
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reconfigure_paused_miner() {
+        let cfg = MinerConfig::from_iter(&["test", "--no-gpu-tune"]);
+        let timeout = Duration::from_secs(5);
+
+        for device in test_devices() {
+            let (target_tx, target_rx) = crate::miner::watch::channel();
+            let (sol_tx, _sol_rx) = futures::channel::mpsc::unbounded();
+            let interface = MinerInterface::new(
+                Address::from_str("k5ztameslf").unwrap(),
+                EventBus::new().emitter(MinerId(0)),
+                target_rx,
+                sol_tx,
+            );
+            let control = interface.control();
+            control.pause();
+
+            let miner = OclMiner::new(device, &cfg).unwrap();
+            let handle = std::thread::spawn(move || Box::new(miner).mine(interface));
+
+            // changes are taken before the first target and while paused
+            assert_eq!(
+                control.reconfigure(Reconfigure::GpuRate(0.05), timeout),
+                Ok(())
+            );
+            target_tx
+                .send(Target {
+                    work: 0,
+                    block: ShortHash::from_str("000000000000").unwrap(),
+                })
+                .unwrap();
+            assert_eq!(
+                control.reconfigure(Reconfigure::GpuRate(0.2), timeout),
+                Ok(())
+            );
+
+            drop(target_tx);
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_vector_width_from_preferred() {
+        let widths: Vec<_> = (0..=16)
+            .map(|p| VectorWidth::from_preferred(p).get())
+            .collect();
+        assert_eq!(widths, [1, 1, 2, 2, 4, 4, 4, 4, 8, 8, 8, 8, 8, 8, 8, 8, 8]);
+        assert!("3".parse::<VectorWidth>().is_err());
+        assert_eq!("4".parse(), Ok(VectorWidth(4)));
+    }
+
+    #[test]
+    fn test_nonce_encoding_matches_cpu() {
+        with_each_miner(|mut miner| {
+            miner
+                .set_target(Target {
+                    work: u64::MAX,
+                    block: ShortHash::from_str("abce8f03b1d2").unwrap(),
+                })
+                .unwrap();
+
+            // with a single work item and impossibly easy work, the kernel
+            // must report exactly the nonce for the given counter
+            for &counter in &[0, 1, 0xdead_beef, u64::MAX] {
+                assert_eq!(
+                    miner.run(counter, 1).unwrap(),
+                    Some(Nonce::from_counter(counter)),
+                    "nonce mismatch on {}",
+                    miner.describe()
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_vector_kernels_match_scalar() {
+        let target = Target {
+            work: 1 << 45,
+            block: ShortHash::from_str("abce8f03b1d2").unwrap(),
+        };
+
+        for device in test_devices() {
+            let mut scalar = OclMiner::new(
+                device.clone(),
+                &MinerConfig::from_iter(&["test", "--no-gpu-tune", "--gpu-vector-width", "1"]),
+            )
+            .unwrap();
+            scalar.set_target(target).unwrap();
+            let solutions: Vec<bool> = (0..256)
+                .map(|c| scalar.run(c, 1).unwrap().is_some())
+                .collect();
+
+            for width in &["2", "4", "8"] {
+                let cfg =
+                    MinerConfig::from_iter(&["test", "--no-gpu-tune", "--gpu-vector-width", width]);
+                let mut miner = OclMiner::new(device.clone(), &cfg).unwrap();
+                miner.set_target(target).unwrap();
+                let lanes = miner.vector_width.get() as u64;
+
+                // each work item must find the first solution among its lanes
+                for counter in (0..256).step_by(lanes as usize) {
+                    let expected = (counter..counter + lanes)
+                        .find(|&c| solutions[c as usize])
+                        .map(Nonce::from_counter);
+                    assert_eq!(
+                        miner.run(counter, 1).unwrap(),
+                        expected,
+                        "mismatch at {} on {}",
+                        counter,
+                        miner.describe()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Identifying and selecting OpenCL devices
+
+use dynamic_ocl::device::DeviceType;
+use enumset::{EnumSet, EnumSetType};
+use itertools::Itertools;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// The PCI address of a device, written like `0000:01:00.0`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PciBusId {
+    pub domain: u32,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Display for PciBusId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.domain, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// An error caused by an invalid device selector
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid device selector: {0:?}")]
+pub struct InvalidSelector(String);
+
+impl FromStr for PciBusId {
+    type Err = InvalidSelector;
+
+    /// Parse a PCI address, with or without the domain
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidSelector(s.to_string());
+
+        let (rest, function) = s.rsplit_once('.').ok_or_else(invalid)?;
+        let mut parts = rest.rsplitn(3, ':');
+        let device = parts.next().ok_or_else(invalid)?;
+        let bus = parts.next().ok_or_else(invalid)?;
+        let domain = parts.next().unwrap_or("0");
+
+        Ok(Self {
+            domain: u32::from_str_radix(domain, 16).map_err(|_| invalid())?,
+            bus: u8::from_str_radix(bus, 16).map_err(|_| invalid())?,
+            device: u8::from_str_radix(device, 16).map_err(|_| invalid())?,
+            function: u8::from_str_radix(function, 16).map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Identifies an OpenCL device found by [`get_opencl_devices`](super::get_opencl_devices)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceId {
+    /// The position of the device in the list of all devices
+    pub index: usize,
+
+    /// The index of the device's platform, and of the device in its platform
+    pub platform: usize,
+    pub device: usize,
+
+    /// The device's PCI address, if the driver reports it
+    pub pci_bus_id: Option<PciBusId>,
+}
+
+impl Display for DeviceId {
+    /// The most stable way to select this device: its PCI address if known,
+    /// otherwise its platform and device indices
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.pci_bus_id {
+            Some(pci) => write!(f, "pci:{}", pci),
+            None => write!(f, "{}:{}", self.platform, self.device),
+        }
+    }
+}
+
+/// Selects OpenCL devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The device at this position in the list of all devices, e.g. `1`
+    Index(usize),
+
+    /// A device by platform and device index, e.g. `0:1`
+    PlatformDevice(usize, usize),
+
+    /// The device at this PCI address, e.g. `pci:0000:01:00.0`
+    Pci(PciBusId),
+
+    /// Devices whose name contains this, ignoring case, e.g. `rx 580`
+    Name(String),
+}
+
+impl DeviceSelector {
+    /// Whether this selects the device with the given ID and name
+    pub fn matches(&self, id: &DeviceId, name: &str) -> bool {
+        match self {
+            DeviceSelector::Index(i) => id.index == *i,
+            DeviceSelector::PlatformDevice(p, d) => id.platform == *p && id.device == *d,
+            DeviceSelector::Pci(pci) => id.pci_bus_id == Some(*pci),
+            DeviceSelector::Name(n) => name.to_lowercase().contains(&n.to_lowercase()),
+        }
+    }
+}
+
+impl FromStr for DeviceSelector {
+    type Err = InvalidSelector;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if s.is_empty() {
+            return Err(InvalidSelector(s.to_string()));
+        }
+
+        if let Some(pci) = s.strip_prefix("pci:") {
+            return Ok(DeviceSelector::Pci(pci.parse()?));
+        }
+
+        if let Ok(index) = s.parse() {
+            return Ok(DeviceSelector::Index(index));
+        }
+
+        let pair = s
+            .split_once(':')
+            .and_then(|(p, d)| Some((p.parse().ok()?, d.parse().ok()?)));
+
+        Ok(match pair {
+            Some((platform, device)) => DeviceSelector::PlatformDevice(platform, device),
+            None => DeviceSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl Display for DeviceSelector {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DeviceSelector::Index(i) => write!(f, "{}", i),
+            DeviceSelector::PlatformDevice(p, d) => write!(f, "{}:{}", p, d),
+            DeviceSelector::Pci(pci) => write!(f, "pci:{}", pci),
+            DeviceSelector::Name(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// A comma-separated list of device selectors, e.g. `0,pci:0000:03:00.0,vega`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceList(pub Vec<DeviceSelector>);
+
+impl FromStr for DeviceList {
+    type Err = InvalidSelector;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Display for DeviceList {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.iter().format(","))
+    }
+}
+
+/// A kind of OpenCL device
+#[derive(Debug, EnumSetType)]
+pub enum DeviceKind {
+    Gpu,
+    /// A CPU exposed through OpenCL, e.g. by POCL or the Intel CPU runtime
+    Cpu,
+    /// An accelerator or custom device, e.g. an FPGA
+    Accelerator,
+}
+
+impl DeviceKind {
+    /// The kind of a device with the given OpenCL type
+    pub fn of(device_type: DeviceType) -> Self {
+        if device_type.contains(DeviceType::GPU) {
+            DeviceKind::Gpu
+        } else if device_type.contains(DeviceType::CPU) {
+            DeviceKind::Cpu
+        } else {
+            DeviceKind::Accelerator
+        }
+    }
+}
+
+impl Display for DeviceKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DeviceKind::Gpu => "gpu",
+            DeviceKind::Cpu => "cpu",
+            DeviceKind::Accelerator => "accelerator",
+        })
+    }
+}
+
+/// The kinds of OpenCL device to mine on, written like `gpu,cpu` or `all`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceKinds(pub EnumSet<DeviceKind>);
+
+impl DeviceKinds {
+    /// Every kind of device
+    pub fn all() -> Self {
+        Self(EnumSet::all())
+    }
+
+    /// Whether devices of the given kind are included
+    pub fn contains(self, kind: DeviceKind) -> bool {
+        self.0.contains(kind)
+    }
+}
+
+impl Default for DeviceKinds {
+    fn default() -> Self {
+        Self(DeviceKind::Gpu.into())
+    }
+}
+
+/// An error caused by an unknown kind of OpenCL device
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid OpenCL device type {0:?}, expected gpu, cpu, accelerator or all")]
+pub struct InvalidDeviceKind(String);
+
+impl FromStr for DeviceKinds {
+    type Err = InvalidDeviceKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kinds = EnumSet::new();
+
+        for kind in s.split(',') {
+            kinds |= match kind.trim().to_lowercase().as_str() {
+                "gpu" => DeviceKind::Gpu.into(),
+                "cpu" => DeviceKind::Cpu.into(),
+                "accelerator" => DeviceKind::Accelerator.into(),
+                "all" => EnumSet::all(),
+                _ => return Err(InvalidDeviceKind(kind.to_string())),
+            };
+        }
+
+        Ok(Self(kinds))
+    }
+}
+
+impl Display for DeviceKinds {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if *self == Self::all() {
+            f.write_str("all")
+        } else {
+            write!(f, "{}", self.0.iter().format(","))
+        }
+    }
+}
+
+/// An error caused by a selector which doesn't match any device
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("no OpenCL device matches {0:?} - see `kristforge info` for the available devices")]
+pub struct NoMatchingDevice(pub String);
+
+/// Keep the devices matched by any of `include` (or all devices if `None`)
+/// and none of `exclude`, in their original order. Every selector in
+/// `include` must match at least one device.
+pub fn select<T>(
+    devices: Vec<T>,
+    id: impl Fn(&T) -> (&DeviceId, &str),
+    include: Option<&DeviceList>,
+    exclude: Option<&DeviceList>,
+) -> Result<Vec<T>, NoMatchingDevice> {
+    let matches_any = |list: &DeviceList, device: &T| {
+        let (id, name) = id(device);
+        list.0.iter().any(|s| s.matches(id, name))
+    };
+
+    if let Some(include) = include {
+        for selector in &include.0 {
+            if !devices.iter().any(|d| {
+                let (id, name) = id(d);
+                selector.matches(id, name)
+            }) {
+                return Err(NoMatchingDevice(selector.to_string()));
+            }
+        }
+    }
+
+    Ok(devices
+        .into_iter()
+        .filter(|d| include.is_none_or(|list| matches_any(list, d)))
+        .filter(|d| !exclude.is_some_and(|list| matches_any(list, d)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(index: usize, platform: usize, pci: Option<&str>, name: &str) -> (DeviceId, String) {
+        let id = DeviceId {
+            index,
+            platform,
+            device: index,
+            pci_bus_id: pci.map(|p| p.parse().unwrap()),
+        };
+
+        (id, name.to_string())
+    }
+
+    #[test]
+    fn test_parse_selectors() {
+        let list: DeviceList = "1, 0:2,pci:03:00.0,Radeon RX 580".parse().unwrap();
+        let pci = PciBusId {
+            domain: 0,
+            bus: 3,
+            device: 0,
+            function: 0,
+        };
+
+        assert_eq!(
+            list.0,
+            vec![
+                DeviceSelector::Index(1),
+                DeviceSelector::PlatformDevice(0, 2),
+                DeviceSelector::Pci(pci),
+                DeviceSelector::Name("Radeon RX 580".to_string()),
+            ]
+        );
+        assert_eq!(list.to_string(), "1,0:2,pci:0000:03:00.0,Radeon RX 580");
+        assert!("pci:nope".parse::<DeviceSelector>().is_err());
+        assert!("0,,1".parse::<DeviceList>().is_err());
+    }
+
+    #[test]
+    fn test_parse_device_kinds() {
+        let kinds: DeviceKinds = "GPU, cpu".parse().unwrap();
+        assert_eq!(kinds.0, DeviceKind::Gpu | DeviceKind::Cpu);
+        assert_eq!(kinds.to_string(), "gpu,cpu");
+        assert_eq!("cpu,all".parse(), Ok(DeviceKinds::all()));
+        assert_eq!(DeviceKinds::all().to_string(), "all");
+        assert!("tpu".parse::<DeviceKinds>().is_err());
+    }
+
+    #[test]
+    fn test_select_devices() {
+        let devices = vec![
+            device(0, 0, Some("0000:01:00.0"), "GeForce GTX 1080"),
+            device(1, 0, Some("0000:02:00.0"), "GeForce GTX 1080"),
+            device(2, 1, None, "Intel UHD Graphics 630"),
+        ];
+        let select = |include: Option<&str>, exclude: Option<&str>| {
+            select(
+                devices.clone(),
+                |(id, name)| (id, name),
+                include.map(|s| s.parse().unwrap()).as_ref(),
+                exclude.map(|s| s.parse().unwrap()).as_ref(),
+            )
+            .map(|selected| selected.iter().map(|(id, _)| id.index).collect::<Vec<_>>())
+        };
+
+        assert_eq!(select(None, None), Ok(vec![0, 1, 2]));
+        assert_eq!(select(Some("gtx"), None), Ok(vec![0, 1]));
+        assert_eq!(select(Some("pci:02:00.0,1:2"), None), Ok(vec![1, 2]));
+        assert_eq!(select(None, Some("intel,0")), Ok(vec![1]));
+        assert_eq!(
+            select(Some("2,vega"), None),
+            Err(NoMatchingDevice("vega".to_string()))
+        );
+
+        assert_eq!(devices[0].0.to_string(), "pci:0000:01:00.0");
+        assert_eq!(devices[2].0.to_string(), "1:2");
+    }
+}
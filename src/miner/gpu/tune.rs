@@ -0,0 +1,293 @@
+//! Choosing the fastest OpenCL kernel configuration for a device by measuring
+//! them
+
+use super::{MiningDevice, OclMiner, VectorWidth};
+use crate::krist::address::Address;
+use crate::krist::block::ShortHash;
+use crate::miner::{cache, MinerConfig, MinerError, Target};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The name of the profile cache file in the data directory
+const CACHE_FILE: &str = "gpu-profiles.json";
+
+/// Local work sizes tried, in addition to letting the driver choose
+const LOCAL_SIZES: [usize; 6] = [32, 64, 128, 256, 512, 1024];
+
+/// The number of cycles timed for each configuration, after a warm-up cycle
+const STEADY_CYCLES: u32 = 3;
+
+/// How the mining kernel is compiled and dispatched on a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelConfig {
+    /// The number of nonces hashed by each work item
+    pub vector_width: VectorWidth,
+
+    /// The local work size, or `None` to let the driver choose
+    pub local_size: Option<usize>,
+
+    /// The work size of the first mining cycle
+    pub work_size: usize,
+}
+
+impl KernelConfig {
+    /// A configuration which starts with a single work item and leaves the
+    /// local work size to the driver
+    pub fn untuned(vector_width: VectorWidth) -> Self {
+        Self {
+            vector_width,
+            local_size: None,
+            work_size: 1,
+        }
+    }
+}
+
+/// The result of tuning a device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    /// The latency budget the device was tuned for, in seconds
+    pub gpu_rate: f32,
+
+    /// The fastest configuration
+    #[serde(flatten)]
+    pub kernel: KernelConfig,
+
+    /// The measured steady-state hashrate of the fastest configuration
+    pub hashrate: f64,
+}
+
+impl Profile {
+    /// Sweep the vector width (unless `config.gpu_vector_width` fixes it),
+    /// local work size and work size of the given device, choosing the
+    /// fastest configuration whose mining cycles fit within `config.gpu_rate`.
+    /// Configurations which fail to build or run are skipped.
+    pub fn measure(device: &MiningDevice, config: &MinerConfig) -> Result<Self, MinerError> {
+        let max_local_size = device.device.max_work_group_size()?;
+        let widths = match config.gpu_vector_width {
+            Some(width) => vec![width],
+            None => VectorWidth::ALL.to_vec(),
+        };
+        let mut results = vec![];
+
+        // find the best work size for each width with the driver's local size
+        for width in widths {
+            let measured =
+                tuning_miner(device, config, KernelConfig::untuned(width)).and_then(|mut miner| {
+                    let work_size = largest_work_size(&mut miner)?;
+                    let hashrate = steady_hashrate(&mut miner, work_size)?;
+                    Ok((miner.kernel_config(work_size), hashrate))
+                });
+
+            match measured {
+                Ok(result) => results.push(result),
+                Err(e) => log::warn!("Skipping {}-wide kernel on {}: {}", width, device.name, e),
+            }
+        }
+
+        let (best, _) = best_result(device, &results)?;
+
+        // then try each local size with the best width and work size
+        let mut miner = tuning_miner(device, config, best)?;
+        for &local_size in LOCAL_SIZES.iter().filter(|&&l| l <= max_local_size) {
+            let work_size = best.work_size / local_size * local_size;
+            if work_size == 0 {
+                continue;
+            }
+
+            miner.local_size = Some(local_size);
+            match steady_hashrate(&mut miner, work_size) {
+                Ok(hashrate) => results.push((miner.kernel_config(work_size), hashrate)),
+                Err(e) => log::warn!(
+                    "Skipping local size {} on {}: {}",
+                    local_size,
+                    device.name,
+                    e
+                ),
+            }
+        }
+
+        log::info!(
+            "Measured OpenCL configurations for {}: {}",
+            device.name,
+            results
+                .iter()
+                .map(|(k, h)| format!(
+                    "{}-wide, local {}, work size {}: {:.2} Mh/s",
+                    k.vector_width,
+                    k.local_size.map_or("auto".to_string(), |l| l.to_string()),
+                    k.work_size,
+                    h / 1_000_000.
+                ))
+                .join("; ")
+        );
+
+        let (kernel, hashrate) = best_result(device, &results)?;
+
+        Ok(Self {
+            gpu_rate: config.gpu_rate,
+            kernel,
+            hashrate,
+        })
+    }
+
+    /// Whether this profile can be reused with the given latency budget
+    fn is_valid_for(&self, gpu_rate: f32) -> bool {
+        self.gpu_rate == gpu_rate
+    }
+}
+
+/// Compile a miner for measuring a configuration, mining a synthetic target
+fn tuning_miner(
+    device: &MiningDevice,
+    config: &MinerConfig,
+    kernel_config: KernelConfig,
+) -> Result<OclMiner, MinerError> {
+    let mut miner = OclMiner::with_kernel_config(device.clone(), config, kernel_config)?;
+    miner.set_address(&Address::from_str("kristforge").unwrap())?;
+    miner.set_target(Target {
+        work: 1,
+        block: ShortHash::from_str("000000000000").unwrap(),
+    })?;
+    Ok(miner)
+}
+
+impl OclMiner {
+    /// This miner's configuration, starting from the given work size
+    fn kernel_config(&self, work_size: usize) -> KernelConfig {
+        KernelConfig {
+            vector_width: self.vector_width,
+            local_size: self.local_size,
+            work_size,
+        }
+    }
+}
+
+/// Time a mining cycle of `work_size` work items, split into sub-ranges like
+/// [`OclMiner::mine`] does
+fn time_cycle(miner: &mut OclMiner, work_size: usize) -> Result<Duration, MinerError> {
+    let sub_range_size = miner.sub_range_size(work_size);
+    let start = Instant::now();
+    let mut done = 0;
+
+    while done < work_size {
+        let size = sub_range_size.min(work_size - done);
+        miner.run(done as u64, size)?;
+        done += size;
+    }
+
+    Ok(start.elapsed())
+}
+
+/// Find the largest work size, doubling from a single work group, whose
+/// cycles take no longer than the miner's target rate
+fn largest_work_size(miner: &mut OclMiner) -> Result<usize, MinerError> {
+    let mut work_size = miner.local_size.unwrap_or(1);
+    time_cycle(miner, work_size)?;
+
+    loop {
+        let next = work_size * 2;
+        if next > miner.max_work_size || time_cycle(miner, next)?.as_secs_f32() > miner.target_rate
+        {
+            return Ok(work_size);
+        }
+
+        work_size = next;
+    }
+}
+
+/// Measure the hashrate of cycles of `work_size` work items once warmed up
+fn steady_hashrate(miner: &mut OclMiner, work_size: usize) -> Result<f64, MinerError> {
+    time_cycle(miner, work_size)?;
+
+    let time = (0..STEADY_CYCLES)
+        .map(|_| time_cycle(miner, work_size))
+        .sum::<Result<Duration, _>>()?;
+
+    let hashes = work_size as u64 * miner.vector_width.get() as u64 * STEADY_CYCLES as u64;
+    Ok(hashes as f64 / time.as_secs_f64())
+}
+
+/// Get the fastest of the configurations measured on a device, or an error if
+/// none of them could be measured
+fn best_result(
+    device: &MiningDevice,
+    results: &[(KernelConfig, f64)],
+) -> Result<(KernelConfig, f64), MinerError> {
+    results
+        .iter()
+        .copied()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .ok_or_else(|| MinerError::TuningFailed(device.name.clone()))
+}
+
+/// Get the key a device's profile is cached under, which depends on whether
+/// the vector width was tuned or fixed
+fn device_key(device: &MiningDevice, width: Option<VectorWidth>) -> Result<String, MinerError> {
+    Ok(format!(
+        "{} (driver {}), width {}",
+        device.name,
+        device.device.driver_version()?.to_string_lossy(),
+        width.map_or("auto".to_string(), |w| w.to_string())
+    ))
+}
+
+/// Get the cached profile of a device, if it has been tuned with this driver,
+/// version, latency budget and vector width before
+pub fn cached_kernel_config(
+    device: &MiningDevice,
+    config: &MinerConfig,
+) -> Result<Option<KernelConfig>, MinerError> {
+    let cache_path = crate::data_dir().map(|d| d.join(CACHE_FILE));
+    let key = device_key(device, config.gpu_vector_width)?;
+
+    Ok(cache::get(cache_path.as_deref(), &key, |p: &Profile| {
+        p.is_valid_for(config.gpu_rate)
+    })
+    .map(|p| p.kernel))
+}
+
+/// Get the kernel configuration for a device like [`cached_kernel_config`],
+/// or tune and cache it if it has no valid profile
+pub fn tuned_kernel_config(
+    device: &MiningDevice,
+    config: &MinerConfig,
+) -> Result<KernelConfig, MinerError> {
+    let cache_path = crate::data_dir().map(|d| d.join(CACHE_FILE));
+    let key = device_key(device, config.gpu_vector_width)?;
+
+    let profile = cache::cached(
+        cache_path.as_deref(),
+        &key,
+        |p: &Profile| p.is_valid_for(config.gpu_rate),
+        || {
+            log::info!("Tuning OpenCL device {}", key);
+            Profile::measure(device, config)
+        },
+    )?;
+
+    log::info!("Using OpenCL profile for {}: {:?}", key, profile);
+    Ok(profile.kernel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner::gpu::tests::test_devices;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_measure_each_device() {
+        let config = MinerConfig::from_iter(&["test", "--gpu-rate", "0.02"]);
+
+        for device in test_devices() {
+            let profile = Profile::measure(&device, &config).unwrap();
+            assert!(profile.hashrate > 0., "no hashrate on {}", device.name);
+
+            if let Some(local_size) = profile.kernel.local_size {
+                assert_eq!(profile.kernel.work_size % local_size, 0);
+            }
+        }
+    }
+}
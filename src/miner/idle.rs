@@ -0,0 +1,248 @@
+//! Pausing miners while other processes are using the system
+
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_to_string;
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// Options for only mining while the system is otherwise idle
+#[derive(Debug, Clone, StructOpt)]
+pub struct IdleConfig {
+    /// Pause mining while other processes use more than this percentage of
+    /// total CPU time, e.g. `25%`
+    #[structopt(long)]
+    pub pause_when_busy: Option<LoadThreshold>,
+
+    /// Seconds the system must stay below the threshold before mining resumes
+    #[structopt(long, default_value = "60")]
+    pub idle_cooldown: u64,
+
+    /// How often to check system load, in seconds
+    #[structopt(long, default_value = "5")]
+    pub idle_interval: u64,
+}
+
+/// The share of total CPU time other processes may use before mining pauses,
+/// written as a percentage like `25` or `25%`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadThreshold(f64);
+
+/// An error caused by parsing an invalid load threshold
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid load threshold: {0} (expected a percentage from 0% to 100%)")]
+pub struct InvalidLoadThreshold(String);
+
+impl LoadThreshold {
+    /// The fraction of total CPU time, from 0 to 1
+    pub fn fraction(self) -> f64 {
+        self.0
+    }
+}
+
+impl FromStr for LoadThreshold {
+    type Err = InvalidLoadThreshold;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().trim_end_matches('%').parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(Self(percent / 100.0)),
+            _ => Err(InvalidLoadThreshold(s.to_string())),
+        }
+    }
+}
+
+impl Display for LoadThreshold {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}%", self.0 * 100.0)
+    }
+}
+
+/// A source of system load readings
+pub trait LoadReader {
+    /// The fraction of total CPU time, from 0 to 1, used by other processes
+    /// since the previous reading
+    fn other_load(&mut self) -> io::Result<f64>;
+}
+
+/// Cumulative CPU times, in clock ticks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTimes {
+    /// Time spent busy by all processes
+    pub busy: u64,
+
+    /// Total time, busy or idle
+    pub total: u64,
+
+    /// Time spent busy by this process
+    pub own: u64,
+}
+
+impl CpuTimes {
+    /// Parse the system-wide times from the `cpu` line of `/proc/stat`, and
+    /// this process's times from `/proc/self/stat`
+    pub fn parse(proc_stat: &str, self_stat: &str) -> Option<Self> {
+        let cpu: Vec<u64> = proc_stat
+            .lines()
+            .find(|l| l.starts_with("cpu "))?
+            .split_whitespace()
+            .skip(1)
+            .map(|f| f.parse().ok())
+            .collect::<Option<_>>()?;
+
+        // user nice system idle iowait irq softirq steal, with guest time
+        // already included in user and nice
+        let field = |i: usize| cpu.get(i).copied().unwrap_or(0);
+        let busy = field(0) + field(1) + field(2) + field(5) + field(6) + field(7);
+        let total = busy + field(3) + field(4);
+
+        // the command name may contain spaces, so skip past it first, after
+        // which utime and stime are the 12th and 13th fields
+        let fields: Vec<&str> = self_stat
+            .get(self_stat.rfind(')')? + 1..)?
+            .split_whitespace()
+            .collect();
+        let own = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+
+        Some(Self { busy, total, own })
+    }
+
+    /// The fraction of total CPU time used by other processes between
+    /// `earlier` and these times
+    pub fn other_load_since(&self, earlier: &Self) -> f64 {
+        let total = self.total.saturating_sub(earlier.total);
+        let busy = self.busy.saturating_sub(earlier.busy);
+        let own = self.own.saturating_sub(earlier.own);
+
+        if total == 0 {
+            0.
+        } else {
+            (busy.saturating_sub(own) as f64 / total as f64).min(1.)
+        }
+    }
+}
+
+/// Reads system load from Linux's `/proc`
+#[derive(Debug)]
+pub struct ProcLoadReader {
+    last: CpuTimes,
+}
+
+impl ProcLoadReader {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            last: Self::read()?,
+        })
+    }
+
+    fn read() -> io::Result<CpuTimes> {
+        let proc_stat = read_to_string("/proc/stat")?;
+        let self_stat = read_to_string("/proc/self/stat")?;
+        CpuTimes::parse(&proc_stat, &self_stat)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid /proc stat file"))
+    }
+}
+
+impl LoadReader for ProcLoadReader {
+    fn other_load(&mut self) -> io::Result<f64> {
+        let times = Self::read()?;
+        let load = times.other_load_since(&self.last);
+        self.last = times;
+        Ok(load)
+    }
+}
+
+/// Decides when to pause and resume mining from a series of load readings
+#[derive(Debug, Clone)]
+pub struct IdleMonitor {
+    threshold: f64,
+    cooldown: Duration,
+    last_busy: Option<Instant>,
+}
+
+impl IdleMonitor {
+    /// Create a monitor which pauses while load is above `threshold` (from 0
+    /// to 1), and resumes once it's been below it for `cooldown`
+    pub fn new(threshold: f64, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            last_busy: None,
+        }
+    }
+
+    /// Whether mining is currently paused
+    pub fn is_paused(&self) -> bool {
+        self.last_busy.is_some()
+    }
+
+    /// Update the monitor with a load reading taken at `now`, returning
+    /// whether mining should be paused
+    pub fn update(&mut self, load: f64, now: Instant) -> bool {
+        if load > self.threshold {
+            self.last_busy = Some(now);
+        } else if let Some(last_busy) = self.last_busy {
+            if now.saturating_duration_since(last_busy) >= self.cooldown {
+                self.last_busy = None;
+            }
+        }
+
+        self.is_paused()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_load_threshold() {
+        let parse = |s: &str| s.parse::<LoadThreshold>().ok().map(|t| t.fraction());
+        assert_eq!(parse("25"), Some(0.25));
+        assert_eq!(parse(" 50% "), Some(0.5));
+        assert_eq!(parse("0"), Some(0.));
+        assert_eq!(parse("100%"), Some(1.));
+        assert_eq!(parse("-5"), None);
+        assert_eq!(parse("150"), None);
+        assert_eq!(parse("NaN"), None);
+        assert_eq!(parse("busy"), None);
+    }
+
+    #[test]
+    fn test_idle_monitor() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut monitor = IdleMonitor::new(0.25, Duration::from_secs(60));
+
+        assert!(!monitor.update(0.1, at(0)));
+        assert!(monitor.update(0.5, at(5)));
+        assert!(monitor.update(0.1, at(30)));
+
+        // a busy reading during the cool-down restarts it
+        assert!(monitor.update(0.3, at(40)));
+        assert!(monitor.update(0.1, at(90)));
+        assert!(!monitor.update(0.1, at(100)));
+    }
+
+    #[test]
+    fn test_cpu_times() {
+        let proc_stat = "cpu  100 0 50 800 50 0 0 0 0 0\ncpu0 100 0 50 800 50 0 0 0 0 0\n";
+        let self_stat = "1234 (kristforge (x)) S 1 1 1 0 -1 4194560 0 0 0 0 30 10 0 0 20 0";
+        let earlier = CpuTimes::parse(proc_stat, self_stat).unwrap();
+        assert_eq!(
+            earlier,
+            CpuTimes {
+                busy: 150,
+                total: 1000,
+                own: 40
+            }
+        );
+
+        let later = CpuTimes {
+            busy: 450,
+            total: 2000,
+            own: 240,
+        };
+        assert_eq!(later.other_load_since(&earlier), 0.1);
+    }
+}
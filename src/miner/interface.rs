@@ -1,123 +1,553 @@
-use super::Target;
+//! The interface between miners and the rest of the application
+
+use super::control::{Counters, MinerControl, PendingChange};
+use super::events::{EventEmitter, EventKind};
+use super::nonces::{NonceAllocator, NonceRange};
+use super::pause::{Pause, PauseReason};
+use super::watch::{self, Epoch, TryRecvError};
+use super::{Solution, Target};
 use crate::krist::address::Address;
-use crossbeam::channel::{Receiver, TryRecvError};
+use crate::krist::nonce::Nonce;
+use crossbeam::atomic::AtomicCell;
+use crossbeam::channel::{Receiver, Sender};
 use futures::channel::mpsc::UnboundedSender;
-use indicatif::ProgressBar;
 use std::cmp::min;
 use std::convert::TryInto;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const PREFIXES: [&str; 5] = ["", "k", "M", "G", "T"];
+
+/// Format a hashrate with an SI prefix, e.g. `1.5 Mh/s`
+pub fn format_hashrate(per_second: f64) -> String {
+    let magnitude = if per_second >= 1. {
+        min(PREFIXES.len() - 1, per_second.log(1000.).floor() as usize)
+    } else {
+        0
+    };
+    let value = per_second / 1000f64.powf(magnitude as f64);
+
+    format!("{:.1} {}h/s", value, PREFIXES[magnitude])
+}
+
+/// Strip `suffix` from the end of `s`, ignoring ASCII case
+fn strip_suffix_ignore_case<'a>(s: &'a str, suffix: &str) -> Option<&'a str> {
+    let split = s.len().checked_sub(suffix.len())?;
+    s.get(split..)?
+        .eq_ignore_ascii_case(suffix)
+        .then(|| &s[..split])
+}
+
+/// Parse a hashrate with an optional SI prefix and unit in any case, e.g.
+/// `1.5M`, `2g` or `250 kh/s`
+pub fn parse_hashrate(s: &str) -> Result<f64, String> {
+    let trimmed = s.trim();
+    let trimmed = strip_suffix_ignore_case(trimmed, "h/s")
+        .unwrap_or(trimmed)
+        .trim_end();
 
+    let (number, multiplier) = PREFIXES
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find_map(|(i, p)| {
+            strip_suffix_ignore_case(trimmed, p).map(|n| (n, 1000f64.powi(i as i32)))
+        })
+        .unwrap_or((trimmed, 1.));
+
+    match number.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() && n >= 0. => Ok(n * multiplier),
+        _ => Err(format!("invalid hashrate: {}", s)),
+    }
+}
+
+/// The interface given to a miner, used to receive new targets and report
+/// progress and solutions
 pub struct MinerInterface {
     address: Address,
-    pb: ProgressBar,
-    target_rx: Receiver<Target>,
+    events: EventEmitter,
+    target_rx: watch::Receiver<Target>,
     target: Option<Target>,
-    solution_tx: UnboundedSender<String>,
+    solution_tx: UnboundedSender<Solution>,
+    pause: Pause,
+    paused_for: Vec<PauseReason>,
+    nonces: NonceAllocator,
+
+    /// The latest target received by this interface or any of its forks
+    latest_target: Arc<AtomicCell<Option<Target>>>,
+
+    /// When this interface or any of its forks last showed the miner is
+    /// still working
+    last_active: Arc<AtomicCell<Instant>>,
+
+    /// Set to make this interface tell its miner to stop
+    stop: Arc<AtomicBool>,
+
+    /// Pauses only this miner, through its [`MinerControl`]
+    own_pause: Pause,
+    counters: Arc<Mutex<Counters>>,
+    changes: (Sender<PendingChange>, Receiver<PendingChange>),
 }
 
+/// Returned by `MinerInterface` when the miner should stop mining
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StopMining;
 
+/// The state of the mining target, as returned by
+/// [`MinerInterface::current_target`]
 #[derive(Debug, Clone, Copy)]
 pub enum CurrentTarget {
+    /// The target has changed since it was last checked
     New(Target),
+
+    /// The target is the same as when it was last checked
     Unchanged(Target),
+
+    /// The miner should pause until the target is next checked
+    Paused,
+
+    /// The miner should stop mining
     StopMining,
 }
 
 impl CurrentTarget {
+    /// Get the raw block (as hex) and work for this target, or `None` if
+    /// mining should stop or pause
     pub fn into_raw(self) -> Option<([u8; 12], u64)> {
         match self {
             CurrentTarget::New(t) | CurrentTarget::Unchanged(t) => {
                 Some((t.block.into_hex().as_bytes().try_into().unwrap(), t.work))
             }
-            CurrentTarget::StopMining => None,
+            CurrentTarget::Paused | CurrentTarget::StopMining => None,
         }
     }
 }
 
 impl MinerInterface {
+    /// Create a new interface mining for `address`. New targets should be sent
+    /// through the [`watch`] channel for `target_rx`, which can be cloned for
+    /// other miners - dropping the sender will stop the miner. Solutions are
+    /// sent through `solution_tx`, and events are published through `events`.
     pub fn new(
         address: Address,
-        pb: ProgressBar,
-        target_rx: Receiver<Target>,
-        solution_tx: UnboundedSender<String>,
+        events: EventEmitter,
+        target_rx: watch::Receiver<Target>,
+        solution_tx: UnboundedSender<Solution>,
     ) -> Self {
         Self {
             address,
-            pb,
+            events,
             target_rx,
             target: None,
             solution_tx,
+            pause: Pause::new(),
+            paused_for: vec![],
+            nonces: NonceAllocator::new(),
+            latest_target: Arc::new(AtomicCell::new(None)),
+            last_active: Arc::new(AtomicCell::new(Instant::now())),
+            stop: Arc::new(AtomicBool::new(false)),
+            own_pause: Pause::new(),
+            counters: Arc::default(),
+            changes: crossbeam::channel::unbounded(),
+        }
+    }
+
+    /// Create another interface for the same session, e.g. for a miner
+    /// replacing a failed one. The fork starts from the latest target, and
+    /// can be stopped independently with [`stop_handle`](Self::stop_handle).
+    pub fn fork(&self) -> Self {
+        Self {
+            address: self.address,
+            events: self.events.clone(),
+            target_rx: self.target_rx.clone(),
+            target: self.latest_target.load(),
+            solution_tx: self.solution_tx.clone(),
+            pause: self.pause.clone(),
+            paused_for: vec![],
+            nonces: self.nonces.clone(),
+            latest_target: self.latest_target.clone(),
+            last_active: self.last_active.clone(),
+            stop: Arc::new(AtomicBool::new(false)),
+            own_pause: self.own_pause.clone(),
+            counters: self.counters.clone(),
+            changes: self.changes.clone(),
         }
     }
 
+    /// Get a handle to control this miner while it runs, which also controls
+    /// any forks of this interface
+    pub fn control(&self) -> MinerControl {
+        MinerControl {
+            pause: self.own_pause.clone(),
+            session_pause: self.pause.clone(),
+            counters: self.counters.clone(),
+            changes: self.changes.0.clone(),
+        }
+    }
+
+    /// Take the next reconfiguration requested through [`MinerControl`].
+    /// Miners should check for changes regularly, apply or reject each one,
+    /// and reply to it.
+    pub fn pending_change(&self) -> Option<PendingChange> {
+        self.changes.1.try_recv().ok()
+    }
+
+    /// The epoch counting targets sent to miners. Miners should poll it while
+    /// working, and stop work on their target as soon as it differs from
+    /// [`target_epoch`](Self::target_epoch).
+    pub fn epoch(&self) -> Epoch {
+        self.target_rx.epoch()
+    }
+
+    /// The epoch of the latest target received by this interface
+    pub fn target_epoch(&self) -> u64 {
+        self.target_rx.received_epoch()
+    }
+
+    /// Report that the miner kept working on its target for `elapsed` after
+    /// a newer one was sent
+    pub fn report_stale_work(&self, elapsed: Duration) {
+        self.events.emit(EventKind::StaleWork { elapsed });
+    }
+
+    /// A flag which, once set, tells the miner using this interface to stop
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Whether a target has been received and the miner hasn't shown it's
+    /// still working for at least `timeout`
+    pub fn is_stalled(&self, timeout: Duration) -> bool {
+        self.latest_target.load().is_some() && self.last_active.load().elapsed() >= timeout
+    }
+
+    /// Record that the miner is still working
+    pub fn touch(&self) {
+        self.last_active.store(Instant::now());
+    }
+
+    /// Use the given switch to pause this miner
+    pub fn with_pause(self, pause: Pause) -> Self {
+        Self { pause, ..self }
+    }
+
+    /// Allocate nonces from the given allocator, which should be shared by
+    /// every miner so they never search the same nonces
+    pub fn with_nonces(self, nonces: NonceAllocator) -> Self {
+        Self { nonces, ..self }
+    }
+
+    /// The allocator this miner should take nonces from
+    pub fn nonces(&self) -> &NonceAllocator {
+        &self.nonces
+    }
+
+    /// Allocate a range of `len` nonces for the current target. Panics if no
+    /// target has been received yet.
+    pub fn allocate_nonces(&self, len: u64) -> NonceRange {
+        let target = self.target.expect("no target received yet");
+        self.nonces.allocate(target.block, len)
+    }
+
+    /// The address being mined for
     pub fn address(&self) -> Address {
         self.address
     }
 
-    /// Get the current target, blocking the thread if necessary
+    /// The emitter used to publish this miner's events
+    pub fn events(&self) -> &EventEmitter {
+        &self.events
+    }
+
+    /// Get the current target, blocking the thread until the first target is
+    /// received. Returns [`CurrentTarget::Paused`] while paused, and
+    /// [`CurrentTarget::New`] once mining resumes.
     pub fn current_target(&mut self) -> CurrentTarget {
+        if self.stop.load(Ordering::Relaxed) {
+            return CurrentTarget::StopMining;
+        }
+
+        let current = self.receive_target();
+
+        if let CurrentTarget::StopMining = current {
+            return current;
+        }
+
+        let mut reasons = self.pause.reasons();
+        reasons.extend(self.own_pause.reasons());
+        reasons.sort();
+        reasons.dedup();
+
+        if reasons != self.paused_for {
+            if reasons.is_empty() {
+                self.events.emit(EventKind::Resumed);
+            } else {
+                self.events.emit(EventKind::Paused {
+                    reasons: reasons.clone(),
+                });
+            }
+        }
+
+        let was_paused = !std::mem::replace(&mut self.paused_for, reasons).is_empty();
+
+        match current {
+            _ if !self.paused_for.is_empty() => {
+                self.touch();
+                CurrentTarget::Paused
+            }
+            CurrentTarget::Unchanged(t) if was_paused => CurrentTarget::New(t),
+            current => current,
+        }
+    }
+
+    /// Get the current target like [`current_target`](Self::current_target),
+    /// but block the thread while paused
+    pub fn wait_for_target(&mut self) -> CurrentTarget {
+        loop {
+            if let Some(current) = self.wait_for_target_timeout(Duration::from_secs(1)) {
+                return current;
+            }
+        }
+    }
+
+    /// Get the current target like [`wait_for_target`](Self::wait_for_target),
+    /// but return `None` if still paused or waiting for the first target after
+    /// `timeout`, so that miners can take pending changes meanwhile
+    pub fn wait_for_target_timeout(&mut self, timeout: Duration) -> Option<CurrentTarget> {
+        if self.target.is_none()
+            && !self.stop.load(Ordering::Relaxed)
+            && !self.target_rx.wait_timeout(timeout)
+        {
+            return None;
+        }
+
+        match self.current_target() {
+            CurrentTarget::Paused if self.own_pause.is_paused() => {
+                self.own_pause.wait_while_paused(timeout);
+                None
+            }
+            CurrentTarget::Paused => {
+                self.pause.wait_while_paused(timeout);
+                None
+            }
+            current => Some(current),
+        }
+    }
+
+    fn receive_target(&mut self) -> CurrentTarget {
         if let Some(old) = self.target {
             match self.target_rx.try_recv() {
-                Ok(target) => {
-                    self.target = Some(target);
-                    CurrentTarget::New(target)
-                }
+                Ok(target) => self.new_target(target),
                 Err(TryRecvError::Empty) => CurrentTarget::Unchanged(old),
                 Err(TryRecvError::Disconnected) => CurrentTarget::StopMining,
             }
         } else {
             match self.target_rx.recv() {
                 Ok(target) => {
-                    self.target = Some(target);
-                    CurrentTarget::New(target)
+                    self.touch();
+                    self.new_target(target)
                 }
                 Err(_) => CurrentTarget::StopMining,
             }
         }
     }
 
+    fn new_target(&mut self, target: Target) -> CurrentTarget {
+        self.target = Some(target);
+        self.latest_target.store(Some(target));
+        CurrentTarget::New(target)
+    }
+
+    /// Report that `hashes` hashes were computed over the period `time`
     pub fn report_speed(&mut self, hashes: u64, time: Duration) {
-        let per_second = hashes as f64 / time.as_secs_f64();
+        self.touch();
 
-        const PREFIXES: [&str; 5] = ["", "k", "M", "G", "T"];
-        let magnitude = min(PREFIXES.len() - 1, per_second.log(1000.).floor() as usize);
-        let value = per_second / 1000f64.powf(magnitude as f64);
+        {
+            let mut counters = self.counters.lock().unwrap();
+            counters.hashrate = hashes as f64 / time.as_secs_f64();
+            counters.total_hashes += hashes;
+        }
 
-        self.pb.set_message(&format!(
-            "Mining at {:.1} {}h/s",
-            value, PREFIXES[magnitude]
-        ));
+        self.events.emit(EventKind::Hashrate {
+            hashes,
+            elapsed: time,
+        });
     }
 
-    pub fn report_solution(&self, solution: String) -> Result<(), StopMining> {
-        log::info!(
-            "Solution reported for address {} and target {:?}: nonce {} (hex: {:x?})",
-            self.address,
-            self.target,
-            solution,
-            solution,
-        );
+    /// Report that each thread computed the given number of hashes over the
+    /// period `time`
+    pub fn report_thread_speeds(&mut self, hashes: Vec<u64>, time: Duration) {
+        self.events.emit(EventKind::ThreadHashrates {
+            hashes,
+            elapsed: time,
+        });
+    }
 
-        self.pb.println(format!(
-            "Submitting solution for block {} (nonce {})",
-            self.target.unwrap().block.into_hex(),
-            solution
-        ));
+    /// Report a solution for the current target, returning `Err` if mining
+    /// should stop
+    pub fn report_solution(&self, nonce: Nonce) -> Result<(), StopMining> {
+        self.counters.lock().unwrap().solutions += 1;
+        self.events.emit(EventKind::Solution {
+            target: self.target.unwrap(),
+            nonce,
+        });
 
         // TODO: validate solution
 
         self.solution_tx
-            .unbounded_send(solution)
+            .unbounded_send(Solution {
+                address: self.address,
+                nonce,
+            })
             .map_err(|_| StopMining)
     }
 }
 
-impl Drop for MinerInterface {
-    fn drop(&mut self) {
-        self.pb.finish();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::krist::block::ShortHash;
+    use crate::miner::events::{EventBus, MinerId};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_interface_events() {
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+
+        let (target_tx, target_rx) = crate::miner::watch::channel();
+        let (solution_tx, mut solution_rx) = futures::channel::mpsc::unbounded();
+        let address = Address::from_str("k5ztameslf").unwrap();
+        let target = Target {
+            work: 100_000,
+            block: ShortHash::from_str("abce8f03b1d2").unwrap(),
+        };
+
+        let mut interface =
+            MinerInterface::new(address, bus.emitter(MinerId(1)), target_rx, solution_tx);
+
+        target_tx.send(target).unwrap();
+        assert!(matches!(interface.current_target(), CurrentTarget::New(t) if t == target));
+
+        interface.report_speed(1000, Duration::from_secs(2));
+        interface.report_solution(Nonce::from_counter(5)).unwrap();
+
+        let kinds: Vec<_> = events.try_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Hashrate {
+                    hashes: 1000,
+                    elapsed: Duration::from_secs(2)
+                },
+                EventKind::Solution {
+                    target,
+                    nonce: Nonce::from_counter(5)
+                }
+            ]
+        );
+
+        assert_eq!(
+            solution_rx.try_next().unwrap(),
+            Some(Solution {
+                address,
+                nonce: Nonce::from_counter(5)
+            })
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {}
+    #[test]
+    fn test_wait_for_target_timeout() {
+        let (target_tx, target_rx) = crate::miner::watch::channel();
+        let (solution_tx, _solution_rx) = futures::channel::mpsc::unbounded();
+        let address = Address::from_str("k5ztameslf").unwrap();
+        let bus = EventBus::new();
+        let mut interface =
+            MinerInterface::new(address, bus.emitter(MinerId(0)), target_rx, solution_tx);
+        let control = interface.control();
+        let timeout = Duration::from_millis(10);
+        let target = Target {
+            work: 100_000,
+            block: ShortHash::from_str("abce8f03b1d2").unwrap(),
+        };
+
+        // gives up before the first target and while paused
+        assert!(interface.wait_for_target_timeout(timeout).is_none());
+        target_tx.send(target).unwrap();
+        assert!(matches!(
+            interface.wait_for_target_timeout(timeout),
+            Some(CurrentTarget::New(t)) if t == target
+        ));
+
+        control.pause();
+        assert!(interface.wait_for_target_timeout(timeout).is_none());
+        control.resume();
+        assert!(matches!(
+            interface.wait_for_target_timeout(timeout),
+            Some(CurrentTarget::New(t)) if t == target
+        ));
+
+        drop(target_tx);
+        assert!(matches!(
+            interface.wait_for_target_timeout(timeout),
+            Some(CurrentTarget::StopMining)
+        ));
+    }
+
+    #[test]
+    fn test_busy_miner_gets_latest_target() {
+        let (target_tx, target_rx) = crate::miner::watch::channel();
+        let (solution_tx, _solution_rx) = futures::channel::mpsc::unbounded();
+        let address = Address::from_str("k5ztameslf").unwrap();
+        let bus = EventBus::new();
+        let mut busy = MinerInterface::new(
+            address,
+            bus.emitter(MinerId(0)),
+            target_rx.clone(),
+            solution_tx.clone(),
+        );
+        let mut idle =
+            MinerInterface::new(address, bus.emitter(MinerId(1)), target_rx, solution_tx);
+
+        let targets: Vec<_> = (1..=3)
+            .map(|work| Target {
+                work,
+                block: ShortHash::from_str("abce8f03b1d2").unwrap(),
+            })
+            .collect();
+
+        target_tx.send(targets[0]).unwrap();
+        assert!(matches!(idle.current_target(), CurrentTarget::New(t) if t == targets[0]));
+
+        // targets sent while a miner isn't checking replace each other
+        target_tx.send(targets[1]).unwrap();
+        target_tx.send(targets[2]).unwrap();
+        assert!(matches!(busy.current_target(), CurrentTarget::New(t) if t == targets[2]));
+        assert!(matches!(idle.current_target(), CurrentTarget::New(t) if t == targets[2]));
+        assert!(matches!(busy.current_target(), CurrentTarget::Unchanged(_)));
+
+        drop(target_tx);
+        assert!(matches!(busy.current_target(), CurrentTarget::StopMining));
+    }
+
+    #[test]
+    fn test_format_hashrate() {
+        assert_eq!(format_hashrate(0.), "0.0 h/s");
+        assert_eq!(format_hashrate(999.), "999.0 h/s");
+        assert_eq!(format_hashrate(1_500_000.), "1.5 Mh/s");
+    }
+
+    #[test]
+    fn test_parse_hashrate() {
+        assert_eq!(parse_hashrate("1500"), Ok(1500.));
+        assert_eq!(parse_hashrate("1.5M"), Ok(1_500_000.));
+        assert_eq!(parse_hashrate("250 kh/s"), Ok(250_000.));
+        assert_eq!(parse_hashrate("2 Gh/s"), Ok(2_000_000_000.));
+        assert_eq!(parse_hashrate("1.5m"), Ok(1_500_000.));
+        assert_eq!(parse_hashrate("2g"), Ok(2_000_000_000.));
+        assert_eq!(parse_hashrate("250 KH/S"), Ok(250_000.));
+        assert!(parse_hashrate("fast").is_err());
+        assert!(parse_hashrate("-5k").is_err());
+    }
+}
@@ -1,80 +1,293 @@
+//! CPU and GPU miners
+
+pub mod bench;
+pub mod cache;
+pub mod control;
 pub mod cpu;
+pub mod events;
 pub mod gpu;
+pub mod idle;
 pub mod interface;
+pub mod nonces;
+pub mod pause;
+pub mod priority;
+pub mod schedule;
+pub mod supervisor;
+pub mod throttle;
+pub mod watch;
 
 use crate::krist::address::Address;
 use crate::krist::block::ShortHash;
+use crate::krist::nonce::{InvalidNonce, Nonce};
+use crate::miner::cpu::topology::{AffinityPolicy, CpuList};
 use crate::miner::cpu::{CpuMiner, KernelType};
-use crate::miner::gpu::OclMiner;
+use crate::miner::events::EventKind;
+use crate::miner::gpu::select::{DeviceKind, DeviceKinds, DeviceList, NoMatchingDevice};
+use crate::miner::gpu::{OclMiner, VectorWidth};
 use crate::miner::interface::MinerInterface;
+use crate::miner::priority::Priority;
+use crate::miner::supervisor::MinerFactory;
+use crate::miner::throttle::UsageLimit;
 use structopt::StructOpt;
 
+/// Configuration used to create miners
 #[derive(Debug, Clone, StructOpt)]
 pub struct MinerConfig {
     /// Don't use OpenCL for mining.
     #[structopt(long)]
-    no_gpu: bool,
-    // TODO: allow selecting individual devices
+    pub no_gpu: bool,
+
+    /// Kinds of OpenCL device to mine on: gpu, cpu, accelerator or all,
+    /// separated by commas.
+    #[structopt(long, default_value = "gpu")]
+    pub opencl_device_types: DeviceKinds,
+
+    /// Only mine on these OpenCL devices: indexes, `platform:device` pairs,
+    /// PCI addresses like `pci:0000:01:00.0` or name substrings, separated by
+    /// commas. See `kristforge info` for each device's identifiers.
+    #[structopt(long)]
+    pub gpu_devices: Option<DeviceList>,
+
+    /// Don't mine on these OpenCL devices, selected like `--gpu-devices`.
+    #[structopt(long)]
+    pub exclude_gpu_devices: Option<DeviceList>,
+
     /// OpenCL miner target kernel execution time, in seconds.
     #[structopt(long, default_value = "0.1")]
-    gpu_rate: f32,
+    pub gpu_rate: f32,
+
+    /// Nonces hashed by each OpenCL work item using vector types: 1, 2, 4 or
+    /// 8. The autotuner then only tunes the local work size and work size.
+    #[structopt(long)]
+    pub gpu_vector_width: Option<VectorWidth>,
+
+    /// Don't autotune the vector width, local work size and work size of
+    /// OpenCL devices, using `--gpu-vector-width` or the device's preferred
+    /// vector width and starting from a work size of 1. Tuned profiles are
+    /// cached in the data directory.
+    #[structopt(long)]
+    pub no_gpu_tune: bool,
 
     /// OpenCL miner max work size (default 2^31).
     #[structopt(long, default_value = "2147483648")]
-    gpu_max_worksize: usize,
+    pub gpu_max_worksize: usize,
+
+    /// Scheduling priority of the OpenCL miner host threads: normal, idle or a
+    /// nice level from -20 to 19.
+    #[structopt(long, default_value = "normal")]
+    pub gpu_priority: Priority,
+
+    /// Maximum fraction of time each OpenCL device spends mining, e.g. `50%`.
+    #[structopt(long, default_value = "100%")]
+    pub gpu_max_usage: UsageLimit,
 
     /// Don't use the CPU for mining.
     #[structopt(long)]
-    no_cpu: bool,
+    pub no_cpu: bool,
 
     /// CPU miner threads, defaulting to the processor's thread count.
     #[structopt(long)]
-    cpu_threads: Option<usize>,
+    pub cpu_threads: Option<usize>,
 
-    /// Select a specific CPU mining kernel.
+    /// Select a specific CPU mining kernel, instead of measuring which is
+    /// fastest.
     #[structopt(long)]
-    cpu_kernel: Option<KernelType>,
+    pub cpu_kernel: Option<KernelType>,
+
+    /// Only mine on the given CPUs, e.g. `0-7,16-23`.
+    #[structopt(long)]
+    pub cpu_cores: Option<CpuList>,
+
+    /// How to pin CPU miner threads to CPUs: none, one-per-core or spread
+    /// (across NUMA nodes). Pinning uses the Linux sysfs CPU topology.
+    #[structopt(long, default_value = "none")]
+    pub cpu_affinity: AffinityPolicy,
+
+    /// Scheduling priority of CPU miner threads: normal, idle (`SCHED_IDLE`
+    /// on Linux) or a nice level from -20 to 19.
+    #[structopt(long, default_value = "5")]
+    pub cpu_priority: Priority,
+
+    /// Maximum fraction of time each CPU miner thread spends mining, e.g.
+    /// `50%`.
+    #[structopt(long, default_value = "100%")]
+    pub cpu_max_usage: UsageLimit,
 }
 
+impl Default for MinerConfig {
+    /// The same defaults used by the command line interface
+    fn default() -> Self {
+        Self {
+            no_gpu: false,
+            opencl_device_types: DeviceKinds::default(),
+            gpu_devices: None,
+            exclude_gpu_devices: None,
+            gpu_rate: 0.1,
+            gpu_vector_width: None,
+            no_gpu_tune: false,
+            gpu_max_worksize: 1 << 31,
+            gpu_priority: Priority::Normal,
+            gpu_max_usage: UsageLimit::UNLIMITED,
+            no_cpu: false,
+            cpu_threads: None,
+            cpu_kernel: None,
+            cpu_cores: None,
+            cpu_affinity: AffinityPolicy::None,
+            cpu_priority: Priority::Nice(5),
+            cpu_max_usage: UsageLimit::UNLIMITED,
+        }
+    }
+}
+
+/// An error that caused a miner to stop
 #[derive(Debug, thiserror::Error)]
 pub enum MinerError {
     #[error("OpenCL error: {0}")]
     OclError(#[from] dynamic_ocl::Error),
+
+    #[error("Miner produced an invalid nonce: {0}")]
+    InvalidNonce(#[from] InvalidNonce),
+
+    #[error("{0}")]
+    NoMatchingDevice(#[from] NoMatchingDevice),
+
+    #[error("The {0} CPU kernel isn't supported by this CPU")]
+    UnsupportedKernel(KernelType),
+
+    #[error("No OpenCL kernel configuration could be measured on {0}")]
+    TuningFailed(String),
 }
 
+/// A block to mine on top of, and the work required to solve it
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Target {
     pub work: u64,
     pub block: ShortHash,
 }
 
+/// A nonce found by a miner, to be submitted for the given address
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Solution {
     pub address: Address,
-    pub nonce: [u8; 12],
+    pub nonce: Nonce,
 }
 
+/// A CPU or GPU miner
 pub trait Miner {
     /// Get a human-readable description of this miner
     fn describe(&self) -> String;
 
+    /// The number of threads this miner reports hashrates for separately with
+    /// [`EventKind::ThreadHashrates`], or 1 if it doesn't
+    fn threads(&self) -> usize {
+        1
+    }
+
     /// Start a long-lived mining operation, blocking the thread and using the
     /// given interface for state operations.
+    ///
+    /// Miners must take changes requested through the interface's
+    /// [`MinerControl`](control::MinerControl) from
+    /// [`MinerInterface::pending_change`] at least every few seconds, and
+    /// reply to each one, rejecting those they don't support.
     fn mine(self: Box<Self>, interface: MinerInterface) -> Result<(), MinerError>;
 }
 
-pub fn create_miners(opts: MinerConfig) -> Result<Vec<Box<dyn Miner + Send>>, MinerError> {
-    let mut miners = Vec::<Box<dyn Miner + Send>>::new();
+/// Run a miner to completion on the current thread, publishing
+/// [`EventKind::Initializing`] first, [`EventKind::Error`] if it fails and
+/// [`EventKind::Stopped`] once it has stopped.
+pub fn run(miner: Box<dyn Miner + Send>, interface: MinerInterface) -> Result<(), MinerError> {
+    let events = interface.events().clone();
+    events.emit(EventKind::Initializing {
+        description: miner.describe(),
+    });
+
+    let result = miner.mine(interface);
+
+    if let Err(e) = &result {
+        events.emit(EventKind::Error(e.to_string()));
+    }
+
+    events.emit(EventKind::Stopped);
+    result
+}
+
+/// A likely mistake in a miner configuration, which doesn't stop it from
+/// mining
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ConfigWarning {
+    #[error(
+        "Both the native CPU miner and an OpenCL CPU device are enabled, so they will compete \
+        for the same cores. Use --no-cpu or --exclude-gpu-devices to only use one of them."
+    )]
+    CompetingCpuMiners,
+}
+
+/// Create factories for miners on all of the hardware enabled by the given
+/// configuration, which can be called again to replace failed miners. Any
+/// [`ConfigWarning`]s are logged.
+pub fn create_miner_factories(opts: MinerConfig) -> Result<Vec<MinerFactory>, MinerError> {
+    let (factories, warnings) = create_miner_factories_with_warnings(opts)?;
+
+    for warning in warnings {
+        log::warn!("{}", warning);
+    }
+
+    Ok(factories)
+}
+
+/// Create factories like [`create_miner_factories`], also returning any
+/// [`ConfigWarning`]s for the caller to show
+pub fn create_miner_factories_with_warnings(
+    opts: MinerConfig,
+) -> Result<(Vec<MinerFactory>, Vec<ConfigWarning>), MinerError> {
+    let mut factories = Vec::<MinerFactory>::new();
+    let mut warnings = vec![];
 
     if !opts.no_gpu {
-        for device in gpu::get_opencl_devices()? {
-            miners.push(Box::new(OclMiner::new(device, &opts)?));
+        let devices = gpu::select::select(
+            gpu::get_opencl_devices(opts.opencl_device_types)?,
+            |d| (d.id(), d.name()),
+            opts.gpu_devices.as_ref(),
+            opts.exclude_gpu_devices.as_ref(),
+        )?;
+
+        if !opts.no_cpu && devices.iter().any(|d| d.kind() == DeviceKind::Cpu) {
+            warnings.push(ConfigWarning::CompetingCpuMiners);
+        }
+
+        for device in devices {
+            let opts = opts.clone();
+            factories.push(Box::new(move || {
+                Ok(Box::new(OclMiner::new(device.clone(), &opts)?) as Box<dyn Miner + Send>)
+            }));
         }
     }
 
     if !opts.no_cpu {
-        miners.push(Box::new(CpuMiner::new(&opts)));
+        factories.push(Box::new(move || Ok(Box::new(CpuMiner::new(&opts)?) as _)));
     }
 
-    Ok(miners)
+    Ok((factories, warnings))
+}
+
+/// Create miners for all of the hardware enabled by the given configuration
+pub fn create_miners(opts: MinerConfig) -> Result<Vec<Box<dyn Miner + Send>>, MinerError> {
+    create_miner_factories(opts)?
+        .iter_mut()
+        .map(|factory| factory())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_matches_cli() {
+        let cli = MinerConfig::from_iter(&["test"]);
+        let default = MinerConfig::default();
+
+        assert_eq!(format!("{:?}", cli), format!("{:?}", default));
+    }
 }
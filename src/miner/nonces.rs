@@ -0,0 +1,312 @@
+//! Handing out disjoint ranges of nonces to miners
+
+use crate::krist::block::ShortHash;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, read_to_string, File};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often progress is saved, at most, for persistent allocators
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A range of nonce counters, which may wrap around at `u64::MAX`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonceRange {
+    /// The first nonce counter in the range
+    pub start: u64,
+
+    /// The number of nonce counters in the range
+    pub len: u64,
+}
+
+impl NonceRange {
+    /// Whether the range contains the given nonce counter
+    pub fn contains(&self, nonce: u64) -> bool {
+        nonce.wrapping_sub(self.start) < self.len
+    }
+}
+
+/// How much of the nonce space has been handed out for a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Progress {
+    block: ShortHash,
+
+    /// The randomly chosen first nonce for this block, so that separate
+    /// machines mining for the same address search different nonces
+    base: u64,
+
+    /// The next nonce to hand out
+    next: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    current: Option<Progress>,
+
+    /// Progress loaded from `path`, if any
+    saved: Option<Progress>,
+
+    /// The number of ranges handed out, used to order saves
+    allocations: u64,
+    last_save: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    path: Option<PathBuf>,
+
+    /// Held while saving, with the allocation count of the progress last
+    /// saved so that older progress never replaces newer progress. Saving
+    /// doesn't hold the state lock, so a slow disk doesn't hold up miners.
+    saved_allocations: Mutex<u64>,
+}
+
+impl Shared {
+    fn save(&self, allocations: u64, progress: Progress) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut saved_allocations = self.saved_allocations.lock().unwrap();
+        if allocations <= *saved_allocations {
+            return;
+        }
+
+        let saved = path
+            .parent()
+            .map_or(Ok(()), create_dir_all)
+            .and_then(|_| File::create(path))
+            .map_err(serde_json::Error::io)
+            .and_then(|f| serde_json::to_writer(f, &progress));
+
+        if let Err(e) = saved {
+            log::warn!("Error saving nonce progress to {}: {}", path.display(), e);
+        }
+
+        *saved_allocations = allocations;
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        if let Some(current) = state.current {
+            let allocations = state.allocations;
+            self.save(allocations, current);
+        }
+    }
+}
+
+/// Hands out disjoint ranges of nonces to every miner working on a block,
+/// starting over whenever the block changes. Clones share the same nonce
+/// space.
+///
+/// Ranges are handed out sequentially from a random starting point, so they
+/// never overlap until all 2^64 nonces for a block have been handed out.
+#[derive(Debug, Clone, Default)]
+pub struct NonceAllocator {
+    shared: Arc<Shared>,
+}
+
+impl NonceAllocator {
+    /// Create an allocator which doesn't save its progress
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an allocator which saves its progress to `path`, resuming from
+    /// any progress already saved there if mining starts on the same block.
+    /// The saved progress should be kept separately for each address.
+    pub fn persistent(path: PathBuf) -> Self {
+        // short hashes deserialize from borrowed strings, so can't be read
+        // straight from the file
+        let saved = read_to_string(&path)
+            .ok()
+            .and_then(|s| match serde_json::from_str(&s) {
+                Ok(progress) => Some(progress),
+                Err(e) => {
+                    log::warn!("Ignoring invalid nonce progress: {}", e);
+                    None
+                }
+            });
+
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    saved,
+                    ..State::default()
+                }),
+                path: Some(path),
+                saved_allocations: Mutex::new(0),
+            }),
+        }
+    }
+
+    /// Allocate a range of `len` nonces for mining on `block`
+    pub fn allocate(&self, block: ShortHash, len: u64) -> NonceRange {
+        let mut state = self.shared.state.lock().unwrap();
+
+        let mut progress = match state.current {
+            Some(current) if current.block == block => current,
+            _ => {
+                let progress = match state.saved.take().filter(|s| s.block == block) {
+                    Some(saved) => {
+                        log::info!(
+                            "Resuming block {} after {} saved nonces",
+                            block,
+                            saved.next.wrapping_sub(saved.base)
+                        );
+                        saved
+                    }
+                    None => {
+                        let base = rand::random();
+                        Progress {
+                            block,
+                            base,
+                            next: base,
+                        }
+                    }
+                };
+
+                // save straight away, so the new block replaces the old one
+                state.last_save = None;
+                progress
+            }
+        };
+
+        let range = NonceRange {
+            start: progress.next,
+            len,
+        };
+
+        progress.next = progress.next.wrapping_add(len);
+        state.current = Some(progress);
+        state.allocations += 1;
+
+        if self.shared.path.is_some()
+            && state.last_save.is_none_or(|t| t.elapsed() >= SAVE_INTERVAL)
+        {
+            state.last_save = Some(Instant::now());
+            let allocations = state.allocations;
+            drop(state);
+            self.shared.save(allocations, progress);
+        }
+
+        range
+    }
+
+    /// The number of nonces handed out for the current block
+    pub fn allocated(&self) -> u64 {
+        let state = self.shared.state.lock().unwrap();
+        state.current.map_or(0, |p| p.next.wrapping_sub(p.base))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_ranges_never_overlap() {
+        let allocator = NonceAllocator::new();
+        let block = ShortHash::from_str("000000000abc").unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let allocator = allocator.clone();
+                std::thread::spawn(move || {
+                    (0..1000)
+                        .map(|j| allocator.allocate(block, 1 + (i * j) % 4096))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut ranges: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+
+        // starting from the only range which doesn't follow another, each
+        // range must start exactly where the previous one ended
+        let ends: HashSet<u64> = ranges.iter().map(|r| r.start.wrapping_add(r.len)).collect();
+        let base = ranges
+            .iter()
+            .find(|r| !ends.contains(&r.start))
+            .unwrap()
+            .start;
+        ranges.sort_by_key(|r| r.start.wrapping_sub(base));
+
+        let mut next = base;
+        for range in &ranges {
+            assert_eq!(range.start, next);
+            next = next.wrapping_add(range.len);
+        }
+
+        let total: u64 = ranges.iter().map(|r| r.len).sum();
+        assert_eq!(allocator.allocated(), total);
+
+        // a new block starts over
+        allocator.allocate(ShortHash::from_str("000000000def").unwrap(), 10);
+        assert_eq!(allocator.allocated(), 10);
+    }
+
+    #[test]
+    fn test_resume_saved_progress() {
+        let dir = std::env::temp_dir().join(format!("kristforge-test-{}", rand::random::<u64>()));
+        let path = dir.join("nonces.json");
+        let block = ShortHash::from_str("000000000abc").unwrap();
+
+        let allocator = NonceAllocator::persistent(path.clone());
+        allocator.allocate(block, 100);
+        let last = allocator.allocate(block, 50);
+        drop(allocator);
+
+        // the same block continues after the last saved range
+        let allocator = NonceAllocator::persistent(path.clone());
+        let next = allocator.allocate(block, 10);
+        assert_eq!(next.start, last.start.wrapping_add(50));
+        assert_eq!(allocator.allocated(), 160);
+        drop(allocator);
+
+        // but a different block starts over
+        let allocator = NonceAllocator::persistent(path);
+        allocator.allocate(ShortHash::from_str("000000000def").unwrap(), 10);
+        assert_eq!(allocator.allocated(), 10);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_saving_doesnt_block_allocation() {
+        let dir = std::env::temp_dir().join(format!("kristforge-test-{}", rand::random::<u64>()));
+        let allocator = NonceAllocator::persistent(dir.join("nonces.json"));
+
+        // a miner starting a new block saves straight away, which waits for
+        // the slow disk held up here
+        let slow_disk = allocator.shared.saved_allocations.lock().unwrap();
+        let saving = {
+            let allocator = allocator.clone();
+            std::thread::spawn(move || {
+                allocator.allocate(ShortHash::from_str("000000000abc").unwrap(), 10)
+            })
+        };
+
+        // other miners can still take ranges meanwhile
+        while allocator.allocated() == 0 {
+            std::thread::yield_now();
+        }
+        allocator.allocate(ShortHash::from_str("000000000abc").unwrap(), 10);
+        assert_eq!(allocator.allocated(), 20);
+
+        drop(slow_disk);
+        saving.join().unwrap();
+        drop(allocator);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Pausing miners without stopping them
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Why mining is paused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PauseReason {
+    /// Paused on request, e.g. by the user or a control tool
+    Manual,
+
+    /// The name being mined for has changed owner
+    NameOwnerChanged,
+
+    /// Other processes are using the system
+    SystemBusy,
+
+    /// It's outside the mining schedule
+    Schedule,
+}
+
+impl Display for PauseReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PauseReason::Manual => "paused manually",
+            PauseReason::NameOwnerChanged => "name owner changed",
+            PauseReason::SystemBusy => "system busy",
+            PauseReason::Schedule => "outside schedule",
+        })
+    }
+}
+
+/// A shared switch to pause miners. Mining is paused while any reason is
+/// active, so independent sources can pause and resume without interfering
+/// with each other.
+#[derive(Debug, Clone, Default)]
+pub struct Pause {
+    inner: Arc<(Mutex<BTreeSet<PauseReason>>, Condvar)>,
+}
+
+impl Pause {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Activate or deactivate a reason to pause, returning whether it changed
+    pub fn set(&self, reason: PauseReason, paused: bool) -> bool {
+        let (reasons, cvar) = &*self.inner;
+        let mut reasons = reasons.lock().unwrap();
+
+        let changed = if paused {
+            reasons.insert(reason)
+        } else {
+            reasons.remove(&reason)
+        };
+
+        if changed {
+            cvar.notify_all();
+        }
+
+        changed
+    }
+
+    /// Pause for the given reason, returning whether it was newly activated
+    pub fn pause(&self, reason: PauseReason) -> bool {
+        self.set(reason, true)
+    }
+
+    /// Stop pausing for the given reason, returning whether it was active
+    pub fn resume(&self, reason: PauseReason) -> bool {
+        self.set(reason, false)
+    }
+
+    /// Whether any reason to pause is active
+    pub fn is_paused(&self) -> bool {
+        !self.inner.0.lock().unwrap().is_empty()
+    }
+
+    /// All active reasons to pause
+    pub fn reasons(&self) -> Vec<PauseReason> {
+        self.inner.0.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Block the thread while paused, for up to `timeout`. Returns whether
+    /// mining is still paused.
+    pub fn wait_while_paused(&self, timeout: Duration) -> bool {
+        let (reasons, cvar) = &*self.inner;
+        let reasons = reasons.lock().unwrap();
+        let (reasons, _) = cvar
+            .wait_timeout_while(reasons, timeout, |r| !r.is_empty())
+            .unwrap();
+        !reasons.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_pause_reasons() {
+        let pause = Pause::new();
+        assert!(!pause.is_paused());
+
+        assert!(pause.pause(PauseReason::Manual));
+        assert!(!pause.pause(PauseReason::Manual));
+        assert!(pause.pause(PauseReason::NameOwnerChanged));
+
+        assert!(pause.resume(PauseReason::Manual));
+        assert!(pause.is_paused());
+        assert_eq!(pause.reasons(), vec![PauseReason::NameOwnerChanged]);
+
+        assert!(pause.resume(PauseReason::NameOwnerChanged));
+        assert!(!pause.is_paused());
+    }
+
+    #[test]
+    fn test_wait_while_paused() {
+        let pause = Pause::new();
+        assert!(!pause.wait_while_paused(Duration::from_secs(10)));
+
+        pause.pause(PauseReason::Manual);
+        assert!(pause.wait_while_paused(Duration::from_millis(10)));
+
+        let remote = pause.clone();
+        let resumer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            remote.resume(PauseReason::Manual);
+        });
+
+        let start = Instant::now();
+        assert!(!pause.wait_while_paused(Duration::from_secs(10)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        resumer.join().unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+//! Scheduling priority for miner threads
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::str::FromStr;
+
+/// The scheduling priority of a miner thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Leave the thread's priority unchanged
+    Normal,
+
+    /// Set the thread's nice level, from -20 (highest priority) to 19 (lowest)
+    Nice(i8),
+
+    /// Only run the thread when the system is otherwise idle, using
+    /// `SCHED_IDLE` on Linux
+    Idle,
+}
+
+/// An error caused by parsing an invalid priority
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid priority: {0} (expected normal, idle or a nice level from -20 to 19)")]
+pub struct InvalidPriority(String);
+
+impl FromStr for Priority {
+    type Err = InvalidPriority;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "normal" => Ok(Self::Normal),
+            "idle" => Ok(Self::Idle),
+            s => match s.parse() {
+                Ok(n) if (-20..=19).contains(&n) => Ok(Self::Nice(n)),
+                _ => Err(InvalidPriority(s.to_string())),
+            },
+        }
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Normal => f.write_str("normal"),
+            Self::Nice(n) => write!(f, "{}", n),
+            Self::Idle => f.write_str("idle"),
+        }
+    }
+}
+
+impl Priority {
+    /// Apply this priority to the current thread, logging a warning if it
+    /// can't be set
+    pub fn apply(self) {
+        if let Err(e) = self.set_current_thread() {
+            log::warn!("Error setting thread priority to {}: {}", self, e);
+        }
+    }
+
+    /// Apply this priority to the current thread
+    #[cfg(windows)]
+    pub fn set_current_thread(self) -> io::Result<()> {
+        use winapi::um::processthreadsapi::*;
+
+        // map nice levels onto the closest Windows thread priorities
+        let priority = match self {
+            Self::Normal => return Ok(()),
+            Self::Nice(n) if n <= -10 => 2,
+            Self::Nice(n) if n < 0 => 1,
+            Self::Nice(0) => 0,
+            Self::Nice(n) if n < 10 => -1,
+            Self::Nice(_) => -2,
+            Self::Idle => -15,
+        };
+
+        unsafe {
+            if SetThreadPriority(GetCurrentThread(), priority) != 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+    }
+
+    /// Apply this priority to the current thread
+    #[cfg(unix)]
+    pub fn set_current_thread(self) -> io::Result<()> {
+        let nice = match self {
+            Self::Normal => return Ok(()),
+            Self::Nice(n) => n,
+            #[cfg(target_os = "linux")]
+            Self::Idle => return set_sched_idle(),
+            #[cfg(not(target_os = "linux"))]
+            Self::Idle => 19,
+        };
+
+        // on Linux, this only affects the calling thread
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice.into()) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Apply this priority to the current thread
+    #[cfg(not(any(windows, unix)))]
+    pub fn set_current_thread(self) -> io::Result<()> {
+        match self {
+            Self::Normal => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "thread priorities aren't supported on this platform",
+            )),
+        }
+    }
+}
+
+/// Switch the current thread to the `SCHED_IDLE` scheduling policy
+#[cfg(target_os = "linux")]
+fn set_sched_idle() -> io::Result<()> {
+    let param = libc::sched_param { sched_priority: 0 };
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_IDLE, &param) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_priority() {
+        assert_eq!(Priority::from_str("normal").unwrap(), Priority::Normal);
+        assert_eq!(Priority::from_str("IDLE").unwrap(), Priority::Idle);
+        assert_eq!(Priority::from_str("5").unwrap(), Priority::Nice(5));
+        assert_eq!(Priority::from_str("-20").unwrap(), Priority::Nice(-20));
+        assert!(Priority::from_str("20").is_err());
+        assert!(Priority::from_str("low").is_err());
+        assert_eq!(Priority::Nice(-3).to_string(), "-3");
+    }
+}
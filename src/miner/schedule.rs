@@ -0,0 +1,272 @@
+//! Only mining during scheduled windows of time
+
+use chrono::{Datelike, Local, NaiveDateTime, Timelike, Utc, Weekday};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// Options for only mining at certain times
+#[derive(Debug, Clone, StructOpt)]
+pub struct ScheduleConfig {
+    /// Only mine during these windows, separated by semicolons, e.g.
+    /// `Mon-Fri 22:00-07:00; Sat,Sun 00:00-24:00`. Windows ending before they
+    /// start run past midnight.
+    #[structopt(long)]
+    pub schedule: Option<Schedule>,
+
+    /// Interpret the schedule in UTC instead of local time
+    #[structopt(long)]
+    pub schedule_utc: bool,
+}
+
+/// A source of the current date and time
+pub trait Clock {
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The system clock in the local time zone
+#[derive(Debug, Clone, Copy)]
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/// The system clock in UTC
+#[derive(Debug, Clone, Copy)]
+pub struct UtcClock;
+
+impl Clock for UtcClock {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+}
+
+/// An error caused by parsing an invalid schedule
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid schedule window: {0:?} (expected e.g. Mon-Fri 22:00-07:00)")]
+pub struct InvalidSchedule(String);
+
+/// A time window repeated on certain days of the week
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// The days the window starts on, indexed from Monday
+    days: [bool; 7],
+
+    /// The start time, in minutes since midnight
+    start: u32,
+
+    /// The end time, in minutes since midnight. If it's not after the start,
+    /// the window ends on the following day.
+    end: u32,
+}
+
+impl Window {
+    /// Whether this window contains the given day and minute of the day
+    fn contains(&self, day: Weekday, minute: u32) -> bool {
+        let today = self.days[day.num_days_from_monday() as usize];
+        let yesterday = self.days[day.pred().num_days_from_monday() as usize];
+
+        if self.start < self.end {
+            today && (self.start..self.end).contains(&minute)
+        } else {
+            (today && minute >= self.start) || (yesterday && minute < self.end)
+        }
+    }
+}
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+fn parse_day(s: &str) -> Option<usize> {
+    let s = s.trim().to_lowercase();
+    DAYS.iter().position(|&d| s.starts_with(d))
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+
+    match (hours, minutes) {
+        (24, 0) => Some(24 * 60),
+        (0..=23, 0..=59) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+impl FromStr for Window {
+    type Err = InvalidSchedule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidSchedule(s.trim().to_string());
+        let mut parts = s.split_whitespace().rev();
+        let times = parts.next().ok_or_else(invalid)?;
+
+        // every day if no days are given
+        let days = match parts.next() {
+            None => [true; 7],
+            Some(list) => {
+                let mut days = [false; 7];
+                for item in list.split(',') {
+                    let (first, last) = match item.split_once('-') {
+                        Some((first, last)) => (first, last),
+                        None => (item, item),
+                    };
+
+                    let first = parse_day(first).ok_or_else(invalid)?;
+                    let last = parse_day(last).ok_or_else(invalid)?;
+
+                    // ranges can wrap around the end of the week, e.g. Sat-Mon
+                    let len = (last + 7 - first) % 7 + 1;
+                    for i in 0..len {
+                        days[(first + i) % 7] = true;
+                    }
+                }
+                days
+            }
+        };
+
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time(start)
+            .filter(|&s| s < 24 * 60)
+            .ok_or_else(invalid)?;
+        let end = parse_time(end).ok_or_else(invalid)?;
+
+        if start == end {
+            return Err(invalid());
+        }
+
+        Ok(Self { days, start, end })
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.days != [true; 7] {
+            let days = DAYS
+                .iter()
+                .zip(&self.days)
+                .filter(|(_, &active)| active)
+                .map(|(day, _)| day);
+
+            for (i, day) in days.enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(f, "{}{}{}", sep, &day[..1].to_uppercase(), &day[1..])?;
+            }
+
+            f.write_str(" ")?;
+        }
+
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// A set of windows of time to mine during
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule(pub Vec<Window>);
+
+impl FromStr for Schedule {
+    type Err = InvalidSchedule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .filter(|w| !w.trim().is_empty())
+            .map(Window::from_str)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, window) in self.0.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "; " };
+            write!(f, "{}{}", sep, window)?;
+        }
+        Ok(())
+    }
+}
+
+impl Schedule {
+    /// Whether mining is scheduled at the given time
+    pub fn is_active(&self, at: NaiveDateTime) -> bool {
+        let minute = at.hour() * 60 + at.minute();
+        self.0.iter().any(|w| w.contains(at.weekday(), minute))
+    }
+
+    /// Whether mining is scheduled at the current time of the given clock
+    pub fn is_active_now(&self, clock: &dyn Clock) -> bool {
+        self.is_active(clock.now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    struct FixedClock(NaiveDateTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> NaiveDateTime {
+            self.0
+        }
+    }
+
+    /// A clock at the given time in the week of Monday 2020-08-03
+    fn at(day: u32, hour: u32, minute: u32) -> FixedClock {
+        FixedClock(NaiveDate::from_ymd(2020, 8, 2 + day).and_hms(hour, minute, 0))
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        let schedule = Schedule::from_str("mon-fri 22:00-07:00; Sat-Mon 10:30-24:00").unwrap();
+        assert_eq!(
+            schedule.to_string(),
+            "Mon,Tue,Wed,Thu,Fri 22:00-07:00; Mon,Sat,Sun 10:30-24:00"
+        );
+        assert_eq!(
+            Schedule::from_str("09:00-17:00").unwrap().to_string(),
+            "09:00-17:00"
+        );
+
+        assert!(Schedule::from_str("Mon 22:00").is_err());
+        assert!(Schedule::from_str("Mon 25:00-07:00").is_err());
+        assert!(Schedule::from_str("Someday 22:00-07:00").is_err());
+        assert!(Schedule::from_str("Mon 07:00-07:00").is_err());
+    }
+
+    #[test]
+    fn test_schedule_windows() {
+        let schedule = Schedule::from_str("Mon-Fri 22:00-07:00; Sun 12:00-24:00").unwrap();
+        let active = |clock: FixedClock| schedule.is_active_now(&clock);
+
+        // overnight windows continue into the next day
+        assert!(!active(at(1, 21, 59)));
+        assert!(active(at(1, 22, 0)));
+        assert!(active(at(2, 6, 59)));
+        assert!(!active(at(2, 7, 0)));
+
+        // Friday night runs into Saturday, but Saturday night isn't scheduled
+        assert!(active(at(6, 3, 0)));
+        assert!(!active(at(6, 23, 0)));
+        assert!(!active(at(7, 3, 0)));
+
+        // Sunday afternoon, but not into Monday morning
+        assert!(active(at(7, 12, 0)));
+        assert!(active(at(7, 23, 59)));
+        assert!(!active(at(8, 0, 0)));
+    }
+}
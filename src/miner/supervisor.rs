@@ -0,0 +1,287 @@
+//! Restarting miners which fail or stop responding
+
+use super::events::EventKind;
+use super::interface::MinerInterface;
+use super::{Miner, MinerError};
+use std::any::Any;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// The longest time to wait before restarting a failed miner
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A miner which runs for this long before failing has its failure count
+/// reset, so occasional errors over a long session don't disable it
+const STABLE_AFTER: Duration = Duration::from_secs(600);
+
+/// Creates a miner, possibly many times as failed miners are replaced
+pub type MinerFactory = Box<dyn FnMut() -> Result<Box<dyn Miner + Send>, MinerError> + Send>;
+
+/// Options for restarting failed miners
+#[derive(Debug, Clone, StructOpt)]
+pub struct SupervisorConfig {
+    /// Restart a miner if it doesn't report its hashrate for this many seconds
+    #[structopt(long, default_value = "60")]
+    pub watchdog_timeout: f64,
+
+    /// Seconds to wait before restarting a failed miner, doubling after each
+    /// consecutive failure
+    #[structopt(long, default_value = "1")]
+    pub restart_delay: f64,
+
+    /// Disable a miner after it fails this many times in a row
+    #[structopt(long, default_value = "5")]
+    pub max_failures: usize,
+}
+
+impl SupervisorConfig {
+    /// How long to wait before restarting a miner after its `failures`th
+    /// consecutive failure
+    pub fn backoff(&self, failures: usize) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31) as i32;
+        Duration::from_secs_f64(self.restart_delay * 2f64.powi(doublings)).min(MAX_BACKOFF)
+    }
+}
+
+/// Get a readable message from a panic payload
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(s) => *s,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+/// Run a miner until it stops, restarting it with miners from `factory` if it
+/// fails, panics or stops reporting its hashrate, and giving up after too
+/// many consecutive failures.
+///
+/// Each miner runs on its own thread using a fork of `interface`. Events are
+/// published like [`run`](super::run) does, except that failures are followed
+/// by [`EventKind::Restarting`], and [`EventKind::Stopped`] is only published
+/// once the miner stops for good.
+pub fn supervise(
+    mut factory: MinerFactory,
+    first: Option<Box<dyn Miner + Send>>,
+    interface: MinerInterface,
+    config: &SupervisorConfig,
+) {
+    let events = interface.events().clone();
+    let watchdog_timeout = Duration::from_secs_f64(config.watchdog_timeout);
+    let mut next = first;
+    let mut failures = 0;
+
+    loop {
+        let started = Instant::now();
+
+        let error = match next.take().map_or_else(&mut factory, Ok) {
+            Err(e) => e.to_string(),
+            Ok(miner) => {
+                events.emit(EventKind::Initializing {
+                    description: miner.describe(),
+                });
+
+                let attempt = interface.fork();
+                let stop = attempt.stop_handle();
+                attempt.touch();
+
+                let handle = std::thread::Builder::new()
+                    .name(format!("{} supervised", events.miner()))
+                    .spawn(move || miner.mine(attempt))
+                    .expect("failed to spawn miner thread");
+
+                loop {
+                    if handle.is_finished() {
+                        match handle.join() {
+                            Ok(Ok(())) => {
+                                events.emit(EventKind::Stopped);
+                                return;
+                            }
+                            Ok(Err(e)) => break e.to_string(),
+                            Err(panic) => break format!("panicked: {}", panic_message(panic)),
+                        }
+                    }
+
+                    // a stuck miner can't be killed, but it's told to stop in
+                    // case it recovers, and its thread is abandoned
+                    if interface.is_stalled(watchdog_timeout) {
+                        stop.store(true, Ordering::Relaxed);
+                        break format!(
+                            "stopped responding for {} seconds",
+                            watchdog_timeout.as_secs_f64()
+                        );
+                    }
+
+                    std::thread::sleep(Duration::from_millis(100).min(watchdog_timeout / 4));
+                }
+            }
+        };
+
+        if started.elapsed() >= STABLE_AFTER {
+            failures = 0;
+        }
+
+        failures += 1;
+        log::warn!(
+            "{} failed ({} in a row): {}",
+            events.miner(),
+            failures,
+            error
+        );
+        events.emit(EventKind::Error(error));
+
+        if failures >= config.max_failures {
+            log::error!(
+                "{} disabled after {} consecutive failures",
+                events.miner(),
+                failures
+            );
+            events.emit(EventKind::Stopped);
+            return;
+        }
+
+        let delay = config.backoff(failures);
+        events.emit(EventKind::Restarting { delay, failures });
+        std::thread::sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::krist::address::Address;
+    use crate::krist::block::ShortHash;
+    use crate::miner::events::{EventBus, MinerId};
+    use crate::miner::interface::CurrentTarget;
+    use crate::miner::Target;
+    use std::str::FromStr;
+
+    /// A miner which fails in different ways
+    enum FakeMiner {
+        Panic,
+        Fail,
+        Hang,
+        Succeed,
+    }
+
+    impl Miner for FakeMiner {
+        fn describe(&self) -> String {
+            "fake".to_string()
+        }
+
+        fn mine(self: Box<Self>, mut interface: MinerInterface) -> Result<(), MinerError> {
+            interface.wait_for_target();
+
+            match *self {
+                FakeMiner::Panic => panic!("oh no"),
+                FakeMiner::Fail => Err(MinerError::InvalidNonce(
+                    crate::krist::nonce::InvalidNonce::InvalidLength(0),
+                )),
+                FakeMiner::Hang => loop {
+                    if let CurrentTarget::StopMining = interface.current_target() {
+                        return Ok(());
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                },
+                FakeMiner::Succeed => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_supervise() {
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+        let (target_tx, target_rx) = crate::miner::watch::channel();
+        let (sol_tx, _sol_rx) = futures::channel::mpsc::unbounded();
+        let address = Address::from_str("k5ztameslf").unwrap();
+        let interface = MinerInterface::new(address, bus.emitter(MinerId(0)), target_rx, sol_tx);
+
+        target_tx
+            .send(Target {
+                work: 0,
+                block: ShortHash::from_str("000000000000").unwrap(),
+            })
+            .unwrap();
+
+        let mut miners = vec![FakeMiner::Succeed, FakeMiner::Hang, FakeMiner::Fail];
+        let factory: MinerFactory =
+            Box::new(move || Ok(Box::new(miners.pop().unwrap()) as Box<dyn Miner + Send>));
+
+        let config = SupervisorConfig {
+            watchdog_timeout: 0.2,
+            restart_delay: 0.01,
+            max_failures: 5,
+        };
+
+        supervise(
+            factory,
+            Some(Box::new(FakeMiner::Panic)),
+            interface,
+            &config,
+        );
+
+        let kinds: Vec<_> = events
+            .try_iter()
+            .map(|e| e.kind)
+            .filter(|k| !matches!(k, EventKind::Initializing { .. }))
+            .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Error("panicked: oh no".to_string()),
+                EventKind::Restarting {
+                    delay: Duration::from_millis(10),
+                    failures: 1
+                },
+                EventKind::Error(
+                    "Miner produced an invalid nonce: invalid nonce length: 0".to_string()
+                ),
+                EventKind::Restarting {
+                    delay: Duration::from_millis(20),
+                    failures: 2
+                },
+                EventKind::Error("stopped responding for 0.2 seconds".to_string()),
+                EventKind::Restarting {
+                    delay: Duration::from_millis(40),
+                    failures: 3
+                },
+                EventKind::Stopped,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled_after_max_failures() {
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+        let (_target_tx, target_rx) = crate::miner::watch::channel();
+        let (sol_tx, _sol_rx) = futures::channel::mpsc::unbounded();
+        let address = Address::from_str("k5ztameslf").unwrap();
+        let interface = MinerInterface::new(address, bus.emitter(MinerId(0)), target_rx, sol_tx);
+
+        let factory: MinerFactory = Box::new(|| {
+            Err(MinerError::InvalidNonce(
+                crate::krist::nonce::InvalidNonce::InvalidLength(1),
+            ))
+        });
+
+        let config = SupervisorConfig {
+            watchdog_timeout: 1.,
+            restart_delay: 0.,
+            max_failures: 3,
+        };
+
+        supervise(factory, None, interface, &config);
+
+        let errors = events
+            .try_iter()
+            .filter(|e| matches!(e.kind, EventKind::Error(_)))
+            .count();
+        assert_eq!(errors, 3);
+    }
+}
@@ -0,0 +1,139 @@
+//! Limiting how much of the time a miner thread spends busy
+
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// A maximum fraction of time a miner may spend busy, written as a percentage
+/// like `50%`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UsageLimit(f64);
+
+/// An error caused by parsing an invalid usage limit
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid usage limit: {0} (expected a percentage from 1% to 100%)")]
+pub struct InvalidUsageLimit(String);
+
+impl UsageLimit {
+    /// No limit, so miners are never throttled
+    pub const UNLIMITED: Self = Self(1.0);
+
+    /// The fraction of time a miner may spend busy, from 0 to 1
+    pub fn fraction(self) -> f64 {
+        self.0
+    }
+
+    /// Create a throttle enforcing this limit, or `None` if it's unlimited
+    pub fn throttle(self) -> Option<Throttle> {
+        if self.0 < 1.0 {
+            Some(Throttle::new(self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for UsageLimit {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
+impl FromStr for UsageLimit {
+    type Err = InvalidUsageLimit;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().trim_end_matches('%').parse::<f64>() {
+            Ok(percent) if (1.0..=100.0).contains(&percent) => Ok(Self(percent / 100.0)),
+            _ => Err(InvalidUsageLimit(s.to_string())),
+        }
+    }
+}
+
+impl Display for UsageLimit {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}%", self.0 * 100.0)
+    }
+}
+
+/// Once this much busy time has been recorded, older history is discounted so
+/// the throttle keeps up with changes in how long each batch takes
+const HISTORY: Duration = Duration::from_secs(2);
+
+/// Keeps a thread's usage under a [`UsageLimit`] by sleeping between batches
+/// of work.
+///
+/// Sleeps are sized from the busy and idle time actually measured, so
+/// batches of varying length and oversleeping are corrected for over time.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    limit: f64,
+    busy: Duration,
+    idle: Duration,
+}
+
+impl Throttle {
+    /// Create a throttle for the given limit
+    pub fn new(limit: UsageLimit) -> Self {
+        Self {
+            limit: limit.fraction(),
+            busy: Duration::from_secs(0),
+            idle: Duration::from_secs(0),
+        }
+    }
+
+    /// Record `busy` time spent working, returning how long to idle for to
+    /// stay under the limit
+    pub fn idle_time(&mut self, busy: Duration) -> Duration {
+        self.busy += busy;
+
+        if self.busy > HISTORY {
+            self.busy /= 2;
+            self.idle /= 2;
+        }
+
+        self.busy
+            .mul_f64((1.0 - self.limit) / self.limit)
+            .checked_sub(self.idle)
+            .unwrap_or_default()
+    }
+
+    /// Record `idle` time spent not working
+    pub fn record_idle(&mut self, idle: Duration) {
+        self.idle += idle;
+    }
+
+    /// Record `busy` time spent working, and sleep long enough to stay under
+    /// the limit
+    pub fn throttle(&mut self, busy: Duration) {
+        let idle = self.idle_time(busy);
+        if idle > Duration::from_secs(0) {
+            let start = Instant::now();
+            std::thread::sleep(idle);
+            self.record_idle(start.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let ms = Duration::from_millis;
+        let mut throttle = UsageLimit::from_str("25%").unwrap().throttle().unwrap();
+
+        assert_eq!(throttle.idle_time(ms(10)), ms(30));
+        throttle.record_idle(ms(40));
+
+        // oversleeping is made up for by idling less afterwards
+        assert_eq!(throttle.idle_time(ms(10)), ms(20));
+        throttle.record_idle(ms(20));
+        assert_eq!(throttle.idle_time(ms(10)), ms(30));
+
+        assert!(UsageLimit::from_str("100%").unwrap().throttle().is_none());
+        assert!(UsageLimit::from_str("0%").is_err());
+        assert_eq!(UsageLimit::from_str("50").unwrap().to_string(), "50%");
+    }
+}
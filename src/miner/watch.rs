@@ -0,0 +1,295 @@
+//! A channel holding only the latest value sent, for broadcasting targets
+//!
+//! Unlike a bounded channel, sending never blocks on slow receivers: each
+//! value replaces the previous one, and every receiver sees the newest value
+//! the next time it checks, skipping any it missed. Each value starts a new
+//! [`Epoch`], which receivers can poll cheaply to notice that their value is
+//! out of date without taking a lock.
+
+use crossbeam::atomic::AtomicCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Returned when sending after every receiver has been dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("sending on a channel with no receivers")]
+pub struct SendError;
+
+/// Returned when receiving after every sender has been dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("receiving on a channel with no senders")]
+pub struct RecvError;
+
+/// Returned by [`Receiver::try_recv`] when there's no new value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TryRecvError {
+    #[error("no new value")]
+    Empty,
+
+    #[error("receiving on a channel with no senders")]
+    Disconnected,
+}
+
+/// Counts the values sent on a channel, starting from 0 before the first
+#[derive(Debug, Clone)]
+pub struct Epoch {
+    inner: Arc<(AtomicU64, AtomicCell<Instant>)>,
+}
+
+impl Epoch {
+    fn new() -> Self {
+        Self {
+            inner: Arc::new((AtomicU64::new(0), AtomicCell::new(Instant::now()))),
+        }
+    }
+
+    /// The number of values sent so far
+    #[inline(always)]
+    pub fn current(&self) -> u64 {
+        self.inner.0.load(Ordering::Acquire)
+    }
+
+    /// When the latest value was sent
+    pub fn started(&self) -> Instant {
+        self.inner.1.load()
+    }
+
+    fn advance(&self) -> u64 {
+        self.inner.1.store(Instant::now());
+        self.inner.0.fetch_add(1, Ordering::Release) + 1
+    }
+}
+
+#[derive(Debug)]
+struct State<T> {
+    value: Option<T>,
+    senders: usize,
+    receivers: usize,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Only advanced while holding the state lock
+    epoch: Epoch,
+    changed: Condvar,
+}
+
+/// Create a channel with no value yet
+pub fn channel<T: Clone>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            value: None,
+            senders: 1,
+            receivers: 1,
+        }),
+        epoch: Epoch::new(),
+        changed: Condvar::new(),
+    });
+
+    let receiver = Receiver {
+        shared: shared.clone(),
+        seen: 0,
+    };
+
+    (Sender { shared }, receiver)
+}
+
+/// Replaces the value seen by every [`Receiver`] of a channel
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Replace the current value without waiting for receivers, failing if
+    /// they have all been dropped
+    pub fn send(&self, value: T) -> Result<(), SendError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError);
+        }
+
+        state.value = Some(value);
+        self.shared.epoch.advance();
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+/// Receives the latest value sent on a channel. Clones start out having seen
+/// the same values as the original.
+#[derive(Debug)]
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// The epoch of the last value received
+    seen: u64,
+}
+
+impl<T> Receiver<T> {
+    /// The epoch counting values sent on this channel
+    pub fn epoch(&self) -> Epoch {
+        self.shared.epoch.clone()
+    }
+
+    /// The epoch of the last value received, which is out of date once it
+    /// differs from [`Epoch::current`]
+    pub fn received_epoch(&self) -> u64 {
+        self.seen
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Get the latest value if it hasn't been received yet, without blocking.
+    /// A value sent before the senders were dropped is still received.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.shared.state.lock().unwrap();
+        let epoch = self.shared.epoch.current();
+
+        match &state.value {
+            Some(value) if epoch > self.seen => {
+                self.seen = epoch;
+                Ok(value.clone())
+            }
+            _ if state.senders == 0 => Err(TryRecvError::Disconnected),
+            _ => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Wait up to `timeout` for a value that hasn't been received yet, without
+    /// receiving it. Returns whether there is one to receive, or the senders
+    /// have all been dropped.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        let (state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, timeout, |s| {
+                (s.value.is_none() || self.shared.epoch.current() <= self.seen) && s.senders > 0
+            })
+            .unwrap();
+
+        (state.value.is_some() && self.shared.epoch.current() > self.seen) || state.senders == 0
+    }
+
+    /// Wait for a value that hasn't been received yet, returning it
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            let epoch = self.shared.epoch.current();
+
+            match &state.value {
+                Some(value) if epoch > self.seen => {
+                    self.seen = epoch;
+                    return Ok(value.clone());
+                }
+                _ if state.senders == 0 => return Err(RecvError),
+                _ => state = self.shared.changed.wait(state).unwrap(),
+            }
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().receivers += 1;
+        Self {
+            shared: self.shared.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receivers -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_slow_receiver_sees_latest() {
+        let (tx, mut rx) = channel();
+        let mut slow = rx.clone();
+
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // sending never waits for receivers to catch up
+        let start = Instant::now();
+        for i in 0..1000 {
+            tx.send(i).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(rx.try_recv(), Ok(999));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        let consumer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            let first = slow.recv().unwrap();
+            let second = slow.recv();
+            (first, second)
+        });
+
+        tx.send(1000).unwrap();
+        tx.send(1001).unwrap();
+        drop(tx);
+
+        assert_eq!(consumer.join().unwrap(), (1001, Err(RecvError)));
+        assert_eq!(rx.try_recv(), Ok(1001));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_epoch() {
+        let (tx, mut rx) = channel();
+        let epoch = rx.epoch();
+        assert_eq!(epoch.current(), 0);
+
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(epoch.current(), 2);
+        assert_eq!(rx.received_epoch(), 0);
+
+        rx.try_recv().unwrap();
+        assert_eq!(rx.received_epoch(), epoch.current());
+        assert!(epoch.started().elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_recv_wakes_on_send() {
+        let (tx, mut rx) = channel();
+
+        let consumer = std::thread::spawn(move || rx.recv());
+        std::thread::sleep(Duration::from_millis(50));
+        tx.send("target").unwrap();
+
+        assert_eq!(consumer.join().unwrap(), Ok("target"));
+        assert_eq!(tx.send("another"), Err(SendError));
+    }
+}
@@ -1,4 +1,6 @@
 use super::NetworkError;
+use crate::krist::address::Address;
+use crate::krist::name::Name;
 use isahc::http::Uri;
 use isahc::ResponseExt;
 use serde::Deserialize;
@@ -14,3 +16,70 @@ pub async fn ws_start(uri: Uri) -> Result<WsStartResponse, NetworkError> {
     let json = isahc::post_async(uri, ()).await?.text_async().await?;
     serde_json::from_str(&json).map_err(|e| e.into())
 }
+
+/// Information about a registered name
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct NameInfo {
+    pub name: Name,
+    pub owner: Address,
+}
+
+#[derive(Debug, Deserialize)]
+struct NameResponse {
+    ok: bool,
+    name: Option<NameInfo>,
+    error: Option<String>,
+}
+
+/// Look up a name. The names API is found relative to the websocket start
+/// endpoint, so `https://krist.ceriat.net/ws/start` looks up names at
+/// `https://krist.ceriat.net/names/<name>`.
+pub async fn get_name(node: &Uri, name: &Name) -> Result<NameInfo, NetworkError> {
+    let url = Url::parse(&node.to_string())?.join(&format!("../names/{}", name.as_str()))?;
+    let json = isahc::get_async(url.as_str()).await?.text_async().await?;
+
+    match serde_json::from_str(&json)? {
+        NameResponse {
+            ok: true,
+            name: Some(info),
+            ..
+        } => Ok(info),
+        NameResponse { error, .. } => Err(NetworkError::ApiError(
+            error.unwrap_or_else(|| "unknown error".to_string()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_name_response() {
+        let json = r#"{
+            "ok": true,
+            "name": {
+                "name": "myshop",
+                "owner": "k5ztameslf",
+                "registered": "2020-01-01T00:00:00.000Z",
+                "updated": "2020-01-01T00:00:00.000Z",
+                "a": null
+            }
+        }"#;
+
+        let response: NameResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            response.name,
+            Some(NameInfo {
+                name: Name::from_str("myshop").unwrap(),
+                owner: Address::from_str("k5ztameslf").unwrap(),
+            })
+        );
+
+        let json = r#"{ "ok": false, "error": "name_not_found" }"#;
+        let response: NameResponse = serde_json::from_str(json).unwrap();
+        assert!(!response.ok);
+        assert_eq!(response.error.as_deref(), Some("name_not_found"));
+    }
+}
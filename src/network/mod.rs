@@ -3,8 +3,12 @@
 mod http;
 mod ws;
 
+pub use http::NameInfo;
+
 use crate::krist::address::Address;
 use crate::krist::block::Block;
+use crate::krist::name::Name;
+use crate::krist::nonce::Nonce;
 use futures::{Sink, TryStream};
 use isahc::http::Uri;
 use serde::de::Error as _;
@@ -13,13 +17,16 @@ use std::collections::HashMap;
 use std::num::NonZeroU64;
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
+/// Configuration for connecting to a krist node
+#[derive(Debug, Clone, StructOpt)]
 pub struct NetConfig {
     /// The krist node to connect to
     #[structopt(short, long, default_value = "https://krist.ceriat.net/ws/start")]
     pub node: Uri,
 }
 
+/// An error communicating with the krist node
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 pub enum NetworkError {
     #[error("HTTP error: {0}")]
@@ -33,8 +40,15 @@ pub enum NetworkError {
 
     #[error("Websocket error: {0}")]
     WsError(#[from] tokio_tungstenite::tungstenite::Error),
+
+    #[error("URL error: {0}")]
+    UrlError(#[from] url::ParseError),
+
+    #[error("Krist API error: {0}")]
+    ApiError(String),
 }
 
+/// The `type` field of keepalive messages
 #[derive(Debug, Clone, Copy)]
 pub struct KeepAliveType;
 
@@ -47,6 +61,7 @@ impl<'de> Deserialize<'de> for KeepAliveType {
     }
 }
 
+/// The `type` field of block submission messages
 #[derive(Debug, Clone, Copy, Default)]
 pub struct SubmitBlockType;
 
@@ -56,6 +71,7 @@ impl Serialize for SubmitBlockType {
     }
 }
 
+/// A message received from the krist node
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum ServerMessage {
@@ -71,6 +87,7 @@ pub enum ServerMessage {
     },
 
     KeepAlive {
+        #[allow(dead_code)]
         #[serde(alias = "type")]
         msg_type: KeepAliveType,
     },
@@ -84,6 +101,7 @@ pub enum ServerMessage {
     },
 }
 
+/// A message sent to the krist node
 #[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum ClientMessage {
@@ -92,12 +110,13 @@ pub enum ClientMessage {
         msg_type: SubmitBlockType,
         id: NonZeroU64,
         address: Address,
-        nonce: String,
+        nonce: Nonce,
     },
 }
 
 impl ClientMessage {
-    pub fn new_solution(address: Address, nonce: String) -> Self {
+    /// Create a block submission message with a random ID
+    pub fn new_solution(address: Address, nonce: Nonce) -> Self {
         ClientMessage::SubmitBlock {
             msg_type: SubmitBlockType,
             id: rand::random(),
@@ -107,6 +126,7 @@ impl ClientMessage {
     }
 }
 
+/// Connect to a krist node, returning halves to send and receive messages
 pub async fn connect(
     cfg: NetConfig,
 ) -> Result<
@@ -119,6 +139,11 @@ pub async fn connect(
     ws::ws_connect(http::ws_start(cfg.node).await?.url).await
 }
 
+/// Look up a name's current owner through the node's names API
+pub async fn get_name(cfg: &NetConfig, name: &Name) -> Result<NameInfo, NetworkError> {
+    http::get_name(&cfg.node, name).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -131,14 +156,14 @@ mod tests {
             "id": 5,
             "type": "submit_block",
             "address": "k5ztameslf",
-            "nonce": "aaaaaaaaaaaaaaa"
+            "nonce": "!          "
         });
 
         let msg = ClientMessage::SubmitBlock {
             id: NonZeroU64::new(5).unwrap(),
             msg_type: SubmitBlockType,
             address: Address::from_str("k5ztameslf").unwrap(),
-            nonce: "aaaaaaaaaaaaaaa".to_string(),
+            nonce: Nonce::from_counter(1),
         };
 
         assert_eq!(json, to_value(&msg).unwrap());
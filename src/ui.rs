@@ -0,0 +1,216 @@
+//! Terminal UI for mining sessions
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use indicatif::ProgressBar;
+use itertools::Itertools;
+use kristforge::krist::estimate::Estimate;
+use kristforge::miner::events::{Event, EventKind, MinerId, ThreadStats};
+use kristforge::miner::interface::format_hashrate;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Describe the expected results of mining at the given combined hashrate,
+/// given the current work and block value
+fn describe_estimate(hashrate: f64, target: Option<(u64, u32)>) -> String {
+    match target {
+        Some((work, value)) => {
+            let estimate = Estimate::new(hashrate, work);
+            format!(
+                "{} at {}, ~{:.1} KST/day",
+                estimate,
+                format_hashrate(hashrate),
+                estimate.kst_per_day(value)
+            )
+        }
+        None => "waiting for target...".to_string(),
+    }
+}
+
+/// Describe a miner's combined hashrate, along with a summary of its threads'
+/// hashrates if it has any, and how long it last spent on a stale target
+fn describe_hashrate(
+    hashrate: f64,
+    threads: Option<&ThreadStats>,
+    stale: Option<Duration>,
+) -> String {
+    let mut message = format!("Mining at {}", format_hashrate(hashrate));
+
+    if let Some(stats) = threads {
+        message += &format!(
+            " (per thread: min {}, mean {}, max {}",
+            format_hashrate(stats.min),
+            format_hashrate(stats.mean),
+            format_hashrate(stats.max)
+        );
+
+        if !stats.slow.is_empty() {
+            message += &format!(
+                " - slow threads: {}",
+                stats.slow.iter().map(|t| format!("#{}", t)).join(", ")
+            );
+        }
+
+        message += ")";
+    }
+
+    if let Some(stale) = stale {
+        message += &format!(
+            " - {:.1} ms stale work on last target",
+            stale.as_secs_f64() * 1000.
+        );
+    }
+
+    message
+}
+
+/// Shows the status of each miner on its progress bar, and the estimate for
+/// the combined hashrate of all miners
+pub struct MinerUi {
+    bars: HashMap<MinerId, ProgressBar>,
+    thread_bars: HashMap<MinerId, Vec<ProgressBar>>,
+    hashrates: HashMap<MinerId, f64>,
+    thread_stats: HashMap<MinerId, ThreadStats>,
+    stale_work: HashMap<MinerId, Duration>,
+    errors: HashMap<MinerId, String>,
+    estimate_pb: ProgressBar,
+    target: Arc<AtomicCell<Option<(u64, u32)>>>,
+}
+
+impl MinerUi {
+    /// Create a UI for the given miner progress bars. `target` holds the
+    /// current work and block value, used for the estimate.
+    pub fn new(
+        bars: HashMap<MinerId, ProgressBar>,
+        estimate_pb: ProgressBar,
+        target: Arc<AtomicCell<Option<(u64, u32)>>>,
+    ) -> Self {
+        Self {
+            bars,
+            thread_bars: HashMap::new(),
+            hashrates: HashMap::new(),
+            thread_stats: HashMap::new(),
+            stale_work: HashMap::new(),
+            errors: HashMap::new(),
+            estimate_pb,
+            target,
+        }
+    }
+
+    /// Also show the hashrate of each of a miner's threads on its own row
+    pub fn with_thread_bars(mut self, miner: MinerId, bars: Vec<ProgressBar>) -> Self {
+        self.thread_bars.insert(miner, bars);
+        self
+    }
+
+    fn handle(&mut self, Event { miner, kind, .. }: Event) {
+        let pb = match self.bars.get(&miner) {
+            Some(pb) => pb,
+            None => return,
+        };
+
+        match kind {
+            EventKind::Initializing { description } => {
+                pb.set_prefix(&description);
+                pb.set_message("Initializing...");
+            }
+            EventKind::Calibrating => pb.set_message("Calibrating..."),
+            EventKind::Hashrate { .. } => {
+                let hashrate = kind.hashrate().unwrap_or_default();
+                self.hashrates.insert(miner, hashrate);
+                pb.set_message(&describe_hashrate(
+                    hashrate,
+                    self.thread_stats.get(&miner),
+                    self.stale_work.get(&miner).copied(),
+                ));
+            }
+            EventKind::ThreadHashrates { .. } => {
+                let hashrates = kind.thread_hashrates().unwrap_or_default();
+                let stats = match ThreadStats::new(&hashrates) {
+                    Some(stats) => stats,
+                    None => return,
+                };
+
+                for (i, pb) in self
+                    .thread_bars
+                    .get(&miner)
+                    .into_iter()
+                    .flatten()
+                    .enumerate()
+                {
+                    let hashrate = hashrates.get(i).copied().unwrap_or_default();
+                    let slow = if stats.slow.contains(&i) {
+                        " - slow"
+                    } else {
+                        ""
+                    };
+                    pb.set_message(&format!("Mining at {}{}", format_hashrate(hashrate), slow));
+                }
+
+                let hashrate = self.hashrates.get(&miner).copied().unwrap_or_default();
+                pb.set_message(&describe_hashrate(
+                    hashrate,
+                    Some(&stats),
+                    self.stale_work.get(&miner).copied(),
+                ));
+                self.thread_stats.insert(miner, stats);
+            }
+            EventKind::StaleWork { elapsed } => {
+                self.stale_work.insert(miner, elapsed);
+            }
+            EventKind::Solution { target, nonce } => pb.println(format!(
+                "Submitting solution for block {} (nonce {})",
+                target.block.into_hex(),
+                nonce
+            )),
+            EventKind::Paused { reasons } => {
+                self.hashrates.remove(&miner);
+                self.thread_stats.remove(&miner);
+                pb.set_message(&format!("Paused ({})", reasons.iter().join(", ")));
+            }
+            EventKind::Resumed => pb.set_message("Resuming..."),
+            EventKind::Error(e) => {
+                self.hashrates.remove(&miner);
+                self.thread_stats.remove(&miner);
+                pb.set_message(&format!("Failed: {}", e));
+                self.errors.insert(miner, e);
+            }
+            EventKind::Restarting { delay, failures } => pb.set_message(&format!(
+                "Failed: {} - restarting in {:.0}s ({} failure{} in a row)",
+                self.errors
+                    .get(&miner)
+                    .map_or("unknown error", String::as_str),
+                delay.as_secs_f64(),
+                failures,
+                if failures == 1 { "" } else { "s" }
+            )),
+            EventKind::Stopped => {
+                self.hashrates.remove(&miner);
+                pb.finish();
+                self.thread_bars
+                    .get(&miner)
+                    .into_iter()
+                    .flatten()
+                    .for_each(ProgressBar::finish);
+            }
+        }
+    }
+
+    /// Update the UI from events on a background thread, until the bus is
+    /// dropped
+    pub fn spawn(mut self, events: Receiver<Event>) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            match events.recv_timeout(Duration::from_secs(1)) {
+                Ok(event) => self.handle(event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            let hashrate = self.hashrates.values().sum();
+            self.estimate_pb
+                .set_message(&describe_estimate(hashrate, self.target.load()));
+        })
+    }
+}